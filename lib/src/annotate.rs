@@ -0,0 +1,695 @@
+use std::{fmt, io::Write, ops::Range};
+
+use termcolor::{Color, ColorSpec, WriteColor};
+use typst_syntax::Tag;
+
+use crate::{fit::Plan, HighlightLevel, Highlighter, SizeUnit, Warning};
+
+/// Highlighted output, annotated with where its styles come from.
+///
+/// It is highlighted once and can then be measured and written according to any plan.
+#[derive(Debug, Clone)]
+pub(crate) struct Annotated {
+    /// The output text, without any escape sequences.
+    text: String,
+    pieces: Vec<Piece>,
+    layers: Vec<Layer>,
+    /// Problems that were worked around while highlighting.
+    pub(crate) warnings: Vec<Warning>,
+    /// The source positions of the raw blocks whose contents are highlighted.
+    pub(crate) raw_blocks: Vec<usize>,
+    /// The language tags of raw blocks that no syntax is known for.
+    pub(crate) unknown_langs: Vec<String>,
+}
+
+/// A part of the output text that is written in the style of one layer.
+#[derive(Debug, Clone)]
+struct Piece {
+    /// The range in the output text.
+    text: Range<usize>,
+    /// The range in the source text, unless the piece is not part of the source.
+    source: Option<Range<usize>>,
+    /// For text that is not part of the source, where it belongs to and its source position.
+    anchor: Option<(Anchor, usize)>,
+    layer: usize,
+}
+
+/// A style that is applied on top of a parent layer.
+#[derive(Debug, Clone)]
+struct Layer {
+    parent: usize,
+    style: Style,
+}
+
+/// Where the style of a layer comes from.
+#[derive(Debug, Clone)]
+pub(crate) enum Style {
+    /// No style at all.
+    Plain,
+    /// The style of a tag.
+    Tag(Tag),
+    /// The style the style hook returned for a node with the tag.
+    Hooked(ColorSpec, Option<Tag>),
+    /// A style of the contents of the raw block at the source position.
+    Raw(usize, ColorSpec),
+    /// The style of the marker that replaces truncated or elided output, that of comments.
+    Marker,
+}
+
+impl Annotated {
+    /// Write the output with the styles of the layers.
+    pub(crate) fn write<W: WriteColor>(
+        &self,
+        styles: &[ColorSpec],
+        mut out: W,
+    ) -> std::io::Result<()> {
+        let mut current = &ColorSpec::new();
+        for piece in &self.pieces {
+            let style = &styles[piece.layer];
+            if style != current {
+                out.set_color(style)?;
+                current = style;
+            }
+            out.write_all(self.text[piece.text.clone()].as_bytes())?;
+        }
+        if !current.is_none() {
+            out.reset()?;
+        }
+        Ok(())
+    }
+
+    /// Write the output with the styles of the layers as ANSI escape sequences.
+    ///
+    /// The output is the same as the one of [`Annotated::write`] to a [`termcolor::Ansi`].
+    pub(crate) fn write_ansi<W: fmt::Write>(
+        &self,
+        styles: &[ColorSpec],
+        mut out: W,
+    ) -> fmt::Result {
+        let mut current = &ColorSpec::new();
+        for piece in &self.pieces {
+            let style = &styles[piece.layer];
+            if style != current {
+                write_sgr(&mut out, style)?;
+                current = style;
+            }
+            out.write_str(&self.text[piece.text.clone()])?;
+        }
+        if !current.is_none() {
+            out.write_str("\x1B[0m")?;
+        }
+        Ok(())
+    }
+
+    /// The size of what [`Annotated::write`] writes with the styles of the layers.
+    pub(crate) fn size(&self, styles: &[ColorSpec], unit: SizeUnit) -> usize {
+        self.size_until(styles, unit, usize::MAX)
+    }
+
+    /// The size of what [`Annotated::write`] writes with the styles of the layers,
+    /// if only the source text before the given position was recorded.
+    ///
+    /// Text that is not part of the source is counted up to the next source text,
+    /// unless it belongs to the source text following it,
+    /// so the size might be a bit larger than the one of the actually recorded output.
+    pub(crate) fn size_until(&self, styles: &[ColorSpec], unit: SizeUnit, end: usize) -> usize {
+        let mut escape_sizes = vec![None; styles.len()];
+        let mut escape_size = |layer: usize| {
+            *escape_sizes[layer].get_or_insert_with(|| {
+                let mut sgr = String::new();
+                write_sgr(&mut sgr, &styles[layer]).expect("writing to a string should not fail");
+                sgr.len()
+            })
+        };
+
+        let mut size = 0;
+        let mut text_end = 0;
+        let mut current = &ColorSpec::new();
+        for piece in &self.pieces {
+            text_end = match &piece.source {
+                Some(source) if source.start >= end => break,
+                None if matches!(piece.anchor, Some((Anchor::Next, pos)) if pos >= end) => break,
+                // Source text is recorded as is, so its ranges have the same length.
+                Some(source) => piece.text.start + (source.end.min(end) - source.start),
+                None => piece.text.end,
+            };
+
+            let style = &styles[piece.layer];
+            if style != current {
+                size += escape_size(piece.layer);
+                current = style;
+            }
+            if piece.source.as_ref().is_some_and(|source| source.end > end) {
+                break;
+            }
+        }
+        // Escape sequences only consist of ASCII characters,
+        // so their size is the same in every unit.
+        size += unit.measure(&self.text[..text_end]);
+        if !current.is_none() {
+            size += "\x1B[0m".len();
+        }
+        size
+    }
+
+    /// The source ranges of the pieces along with their styles, merging adjacent ones.
+    pub(crate) fn spans(&self, styles: &[ColorSpec]) -> Vec<(ColorSpec, Range<usize>)> {
+        let mut spans: Vec<(ColorSpec, Range<usize>)> = Vec::new();
+        for piece in &self.pieces {
+            let Some(source) = piece.source.clone() else {
+                continue;
+            };
+            let style = &styles[piece.layer];
+            match spans.last_mut() {
+                Some((last_style, last)) if last_style == style && last.end == source.start => {
+                    last.end = source.end;
+                }
+                _ => spans.push((style.clone(), source)),
+            }
+        }
+        spans
+    }
+}
+
+/// Write the SGR sequences that set the style, just like [`termcolor::Ansi`] does.
+pub(crate) fn write_sgr<W: fmt::Write>(out: &mut W, spec: &ColorSpec) -> fmt::Result {
+    if spec.reset() {
+        out.write_str("\x1B[0m")?;
+    }
+    let attributes = [
+        (spec.bold(), 1),
+        (spec.dimmed(), 2),
+        (spec.italic(), 3),
+        (spec.underline(), 4),
+        (spec.strikethrough(), 9),
+    ];
+    for (_, code) in attributes.iter().filter(|(set, _)| *set) {
+        write!(out, "\x1B[{code}m")?;
+    }
+    // Foreground colors start with a 3, background colors with a 4.
+    for (color, kind) in [(spec.fg(), 3), (spec.bg(), 4)] {
+        match (color, basic_index(color)) {
+            (Some(Color::Ansi256(index)), _) => write!(out, "\x1B[{kind}8;5;{index}m")?,
+            (Some(Color::Rgb(r, g, b)), _) => write!(out, "\x1B[{kind}8;2;{r};{g};{b}m")?,
+            (_, Some(index)) if spec.intense() => write!(out, "\x1B[{kind}8;5;{}m", index + 8)?,
+            (_, Some(index)) => write!(out, "\x1B[{kind}{index}m")?,
+            (_, None) => {}
+        }
+    }
+    Ok(())
+}
+
+/// The index of one of the eight basic colors.
+fn basic_index(color: Option<&Color>) -> Option<u8> {
+    match color? {
+        Color::Black => Some(0),
+        Color::Red => Some(1),
+        Color::Green => Some(2),
+        Color::Yellow => Some(3),
+        Color::Blue => Some(4),
+        Color::Magenta => Some(5),
+        Color::Cyan => Some(6),
+        Color::White => Some(7),
+        _ => None,
+    }
+}
+
+impl Highlighter {
+    /// The style of each layer according to the plan.
+    pub(crate) fn resolve_styles(&self, annotated: &Annotated, plan: &Plan) -> Vec<ColorSpec> {
+        let mut styles: Vec<ColorSpec> = Vec::with_capacity(annotated.layers.len());
+        for layer in &annotated.layers {
+            // Parents are always created before their children.
+            let inherited = || styles[layer.parent].clone();
+            let style = match &layer.style {
+                Style::Plain => ColorSpec::new(),
+                Style::Tag(tag) => self.tag_to_color(plan, *tag),
+                Style::Hooked(spec, _) if plan.level != HighlightLevel::Off => spec.clone(),
+                Style::Hooked(_, Some(tag)) => self.tag_to_color(plan, *tag),
+                Style::Hooked(_, None) => inherited(),
+                Style::Raw(pos, spec) if plan.highlights_raw(*pos) => spec.clone(),
+                Style::Raw(..) => inherited(),
+                Style::Marker => self.tag_to_color(plan, Tag::Comment),
+            };
+            styles.push(style);
+        }
+        styles
+    }
+}
+
+/// Records the highlighted output along with the layers of its styles.
+///
+/// It also keeps track of the position in the source text,
+/// so that only a range of the source can be recorded.
+pub(crate) struct Annotator {
+    annotated: Annotated,
+    /// The layer of the next recorded text.
+    layer: usize,
+    /// The source position of the next written byte.
+    pub(crate) pos: usize,
+    /// If set, only source text inside of this range is recorded.
+    range: Option<Range<usize>>,
+    /// Ordered source ranges that are replaced by a placeholder.
+    elided: Vec<Range<usize>>,
+    /// The source position that the offsets of written nodes are relative to.
+    ///
+    /// Differs from zero inside of raw blocks containing Typst code.
+    base: usize,
+    /// Whether the last recorded source text ended with a line break.
+    at_line_start: bool,
+}
+
+/// What elided source ranges are replaced by.
+pub(crate) const PLACEHOLDER: &str = "…";
+
+/// Where text that is not part of the source belongs to when only a range is recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Anchor {
+    /// Recorded if the source text following it is.
+    Next,
+    /// Recorded if the source text preceding it is.
+    Previous,
+    /// Always recorded.
+    Always,
+}
+
+impl Annotator {
+    pub(crate) fn new(pos: usize) -> Annotator {
+        Annotator {
+            annotated: Annotated {
+                text: String::new(),
+                pieces: Vec::new(),
+                layers: vec![Layer {
+                    parent: 0,
+                    style: Style::Plain,
+                }],
+                warnings: Vec::new(),
+                raw_blocks: Vec::new(),
+                unknown_langs: Vec::new(),
+            },
+            layer: 0,
+            pos,
+            range: None,
+            elided: Vec::new(),
+            base: 0,
+            at_line_start: false,
+        }
+    }
+
+    pub(crate) fn with_range(mut self, range: Range<usize>) -> Annotator {
+        self.range = Some(range);
+        self
+    }
+
+    /// Replace the given ordered source ranges by a placeholder.
+    pub(crate) fn with_elided(mut self, elided: &[Range<usize>]) -> Annotator {
+        self.elided = elided.to_vec();
+        self
+    }
+
+    pub(crate) fn finish(self) -> Annotated {
+        self.annotated
+    }
+
+    /// The layer of the next recorded text.
+    pub(crate) fn layer(&self) -> usize {
+        self.layer
+    }
+
+    /// Go back to a previous layer.
+    pub(crate) fn set_layer(&mut self, layer: usize) {
+        self.layer = layer;
+    }
+
+    /// Record the following text with a style on top of the current layer.
+    pub(crate) fn push(&mut self, style: Style) {
+        self.annotated.layers.push(Layer {
+            parent: self.layer,
+            style,
+        });
+        self.layer = self.annotated.layers.len() - 1;
+    }
+
+    #[cfg_attr(not(feature = "raw-highlighting"), allow(dead_code))]
+    pub(crate) fn warn(&mut self, warning: Warning) {
+        self.annotated.warnings.push(warning);
+    }
+
+    /// Mark the contents of the raw block at the source position as highlighted.
+    pub(crate) fn add_raw_block(&mut self, pos: usize) {
+        self.annotated.raw_blocks.push(pos);
+    }
+
+    #[cfg_attr(not(feature = "raw-highlighting"), allow(dead_code))]
+    pub(crate) fn add_unknown_lang(&mut self, lang: &str) {
+        if !self
+            .annotated
+            .unknown_langs
+            .iter()
+            .any(|known| known == lang)
+        {
+            self.annotated.unknown_langs.push(lang.to_string());
+        }
+    }
+
+    /// Whether any part of the given node range would be recorded.
+    pub(crate) fn overlaps(&self, range: Range<usize>) -> bool {
+        let range = self.base + range.start..self.base + range.end;
+        self.range
+            .as_ref()
+            .map_or(true, |r| range.start < r.end && r.start < range.end)
+    }
+
+    /// Whether the given node range is elided entirely.
+    pub(crate) fn elides(&self, range: Range<usize>) -> bool {
+        let range = self.base + range.start..self.base + range.end;
+        self.elided
+            .iter()
+            .any(|elided| elided.start <= range.start && range.end <= elided.end)
+    }
+
+    /// Make node ranges relative to the given source position, returning the previous one.
+    pub(crate) fn rebase(&mut self, base: usize) -> usize {
+        std::mem::replace(&mut self.base, base)
+    }
+
+    /// Advance the source position without recording any source text.
+    pub(crate) fn skip(&mut self, len: usize) {
+        let start = self.pos;
+        self.pos += len;
+        let visible = self.visible(start..self.pos);
+        let starts: Vec<usize> = self
+            .elided
+            .iter()
+            .map(|elided| elided.start)
+            .filter(|start| visible.contains(start))
+            .collect();
+        for start in starts {
+            self.write_placeholder(start);
+        }
+    }
+
+    pub(crate) fn at_line_start(&self) -> bool {
+        self.at_line_start
+    }
+
+    /// Record the placeholder of the elided source range starting at the position.
+    fn write_placeholder(&mut self, pos: usize) {
+        self.record_marker(PLACEHOLDER, (Anchor::Next, pos));
+    }
+
+    /// Record a marker for truncated output, which is styled like a comment.
+    pub(crate) fn write_marker(&mut self, marker: &str) {
+        if marker.is_empty() {
+            return;
+        }
+        self.record_marker(marker, (Anchor::Always, self.pos));
+    }
+
+    fn record_marker(&mut self, marker: &str, anchor: (Anchor, usize)) {
+        let prev_layer = self.layer;
+        self.push(Style::Marker);
+        self.record(marker, None, Some(anchor));
+        self.set_layer(prev_layer);
+        self.at_line_start = marker.ends_with('\n');
+    }
+
+    /// Record text that is not part of the source.
+    pub(crate) fn write_extra(&mut self, text: &str, anchor: Anchor) {
+        let visible = match (&self.range, anchor) {
+            (None, _) | (_, Anchor::Always) => true,
+            (Some(range), Anchor::Next) => range.start <= self.pos && self.pos < range.end,
+            (Some(range), Anchor::Previous) => range.start < self.pos && self.pos <= range.end,
+        };
+        if visible {
+            self.record(text, None, Some((anchor, self.pos)));
+        }
+    }
+
+    /// The part of the source range that is recorded.
+    fn visible(&self, source: Range<usize>) -> Range<usize> {
+        match &self.range {
+            Some(range) => {
+                range.start.clamp(source.start, source.end)
+                    ..range.end.clamp(source.start, source.end)
+            }
+            None => source,
+        }
+    }
+
+    fn record(
+        &mut self,
+        text: &str,
+        source: Option<Range<usize>>,
+        anchor: Option<(Anchor, usize)>,
+    ) {
+        let annotated = &mut self.annotated;
+        let start = annotated.text.len();
+        annotated.text.push_str(text);
+        let end = annotated.text.len();
+        match annotated.pieces.last_mut() {
+            Some(last)
+                if last.layer == self.layer
+                    && match (&last.source, &source) {
+                        (Some(last), Some(source)) => last.end == source.start,
+                        (None, None) => last.anchor == anchor,
+                        _ => false,
+                    } =>
+            {
+                last.text.end = end;
+                if let (Some(last), Some(source)) = (&mut last.source, source) {
+                    last.end = source.end;
+                }
+            }
+            _ => annotated.pieces.push(Piece {
+                text: start..end,
+                source,
+                anchor,
+                layer: self.layer,
+            }),
+        }
+    }
+}
+
+impl Write for Annotator {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let start = self.pos;
+        self.pos += buf.len();
+        let visible = self.visible(start..self.pos);
+        if visible.is_empty() {
+            return Ok(buf.len());
+        }
+
+        let text = std::str::from_utf8(&buf[visible.start - start..visible.end - start])
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        let mut cursor = visible.start;
+        while cursor < visible.end {
+            let elided = self
+                .elided
+                .iter()
+                .find(|elided| cursor < elided.end)
+                .cloned();
+            let next = match elided {
+                Some(elided) if elided.start <= cursor => {
+                    if elided.start == cursor {
+                        self.write_placeholder(cursor);
+                    }
+                    cursor = elided.end.min(visible.end);
+                    continue;
+                }
+                Some(elided) => elided.start.min(visible.end),
+                None => visible.end,
+            };
+            let part = &text[cursor - visible.start..next - visible.start];
+            self.at_line_start = part.ends_with('\n');
+            self.record(part, Some(cursor..next), None);
+            cursor = next;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use typst_syntax::LinkedNode;
+
+    use super::*;
+    use crate::fit::Degradation;
+
+    const INPUTS: &[&str] = &[
+        "= Tïtle\n#let f(x) = [*strong* _emph_ #x] // ünïcode\n$a + b^2$ <label> @ref\n",
+        "```rust\nlet s = \"ß→😀\";\n```\n```ansi \x1b[31mred\x1b[0m 🎉```\n#f(\"👩‍👩‍👧\")",
+        "Ends with color: #let x = 1",
+    ];
+
+    /// The levels from the most to the least highlighting.
+    fn levels() -> impl Iterator<Item = HighlightLevel> {
+        std::iter::successors(Some(HighlightLevel::All), |&level| {
+            (level != HighlightLevel::Off).then(|| level.restrict())
+        })
+    }
+
+    /// Plans for every level, along with every single degradation of each tag and raw block.
+    fn plans(annotated: &Annotated) -> Vec<Plan> {
+        let mut plans = Vec::new();
+        for level in levels() {
+            plans.push(Plan::new(level));
+            let degradations = Tag::LIST
+                .iter()
+                .flat_map(|&tag| [Degradation::Color(tag), Degradation::Styles(tag)])
+                .chain(
+                    annotated
+                        .raw_blocks
+                        .iter()
+                        .map(|&pos| Degradation::Raw(pos)),
+                );
+            for degradation in degradations {
+                plans.push(Plan {
+                    level,
+                    degraded: vec![degradation],
+                });
+            }
+        }
+        plans
+    }
+
+    fn highlighters() -> Vec<Highlighter> {
+        let mut discord = Highlighter::default();
+        discord.for_discord();
+        let mut hooked = Highlighter::default();
+        hooked.with_style_hook(|node, _| {
+            (node.text() == "x").then(|| ColorSpec::new().set_fg(Some(Color::Red)).clone())
+        });
+        vec![Highlighter::default(), discord, hooked]
+    }
+
+    #[test]
+    fn test_size_equals_rendered_size() {
+        let units = [
+            SizeUnit::Bytes,
+            SizeUnit::Chars,
+            SizeUnit::Utf16,
+            SizeUnit::Graphemes,
+        ];
+        for highlighter in highlighters() {
+            for input in INPUTS {
+                let parsed = highlighter.parse(input);
+                let linked = LinkedNode::new(&parsed);
+                let annotated = highlighter.annotate(&linked, None, &[], None).unwrap();
+                for plan in plans(&annotated) {
+                    let rendered = highlighter.render(&annotated, &plan).unwrap();
+                    let styles = highlighter.resolve_styles(&annotated, &plan);
+                    for unit in units {
+                        assert_eq!(
+                            annotated.size(&styles, unit),
+                            unit.measure(&rendered),
+                            "{unit:?} {plan:?} {input:?}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_size_until_estimates_rendered_size() {
+        let highlighter = Highlighter::default();
+        for input in INPUTS {
+            let parsed = highlighter.parse(input);
+            let linked = LinkedNode::new(&parsed);
+            let annotated = highlighter.annotate(&linked, None, &[], None).unwrap();
+            for plan in levels().map(Plan::new) {
+                let styles = highlighter.resolve_styles(&annotated, &plan);
+                let size = annotated.size(&styles, SizeUnit::Bytes);
+                assert_eq!(
+                    annotated.size_until(&styles, SizeUnit::Bytes, usize::MAX),
+                    size
+                );
+
+                // Up to a position, the estimate is never below the actual size.
+                for (cut, _) in input.char_indices() {
+                    let truncated = highlighter
+                        .annotate(&linked, Some(0..cut), &[], None)
+                        .unwrap();
+                    let actual = highlighter.render(&truncated, &plan).unwrap().len();
+                    let estimate = annotated.size_until(&styles, SizeUnit::Bytes, cut);
+                    assert!(estimate >= actual, "{cut} {plan:?} {input:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_sgr_equals_termcolor() {
+        let colors = [
+            Color::Black,
+            Color::Red,
+            Color::Green,
+            Color::Yellow,
+            Color::Blue,
+            Color::Magenta,
+            Color::Cyan,
+            Color::White,
+            Color::Ansi256(0),
+            Color::Ansi256(208),
+            Color::Rgb(0, 10, 255),
+        ];
+        let mut specs = vec![ColorSpec::new()];
+        for color in colors.into_iter().map(Some).chain([None]) {
+            for intense in [false, true] {
+                let mut spec = ColorSpec::new();
+                spec.set_fg(color).set_intense(intense);
+                specs.push(spec.clone());
+                spec.set_bg(Some(Color::Blue));
+                specs.push(spec.clone());
+                spec.set_fg(None).set_bg(color);
+                specs.push(spec);
+            }
+        }
+        let mut styled = ColorSpec::new();
+        styled
+            .set_bold(true)
+            .set_dimmed(true)
+            .set_italic(true)
+            .set_underline(true)
+            .set_strikethrough(true)
+            .set_fg(Some(Color::Red));
+        specs.push(styled.clone());
+        specs.push(styled.set_reset(false).clone());
+
+        for spec in specs {
+            let mut expected = termcolor::Ansi::new(Vec::new());
+            expected.set_color(&spec).unwrap();
+            let mut sgr = String::new();
+            write_sgr(&mut sgr, &spec).unwrap();
+            assert_eq!(sgr.as_bytes(), expected.into_inner(), "{spec:?}");
+        }
+    }
+
+    #[test]
+    fn test_write_ansi_equals_write() {
+        for highlighter in highlighters() {
+            for input in INPUTS {
+                let parsed = highlighter.parse(input);
+                let linked = LinkedNode::new(&parsed);
+                let annotated = highlighter.annotate(&linked, None, &[], None).unwrap();
+                for plan in levels().map(Plan::new) {
+                    let styles = highlighter.resolve_styles(&annotated, &plan);
+                    let mut expected = termcolor::Ansi::new(Vec::new());
+                    annotated.write(&styles, &mut expected).unwrap();
+                    let mut output = String::new();
+                    annotated.write_ansi(&styles, &mut output).unwrap();
+                    assert_eq!(
+                        output.as_bytes(),
+                        expected.into_inner(),
+                        "{plan:?} {input:?}"
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::io::Write;
+
+use termcolor::{Color, ColorSpec};
+
+use crate::{
+    annotate::{Annotator, Style},
+    Error,
+};
+
+const ESCAPE: char = '\x1B';
+
+/// Record text containing ANSI escape sequences, replaying its SGR sequences as colors.
+///
+/// The escape sequences themselves are hidden.
+/// The colors belong to the raw block at the source position,
+/// so the current color is kept when it is not highlighted.
+pub(crate) fn highlight_ansi(input: &str, pos: usize, out: &mut Annotator) -> Result<(), Error> {
+    let mut color = ColorSpec::new();
+    out.add_raw_block(pos);
+    let outer = out.layer();
+    out.push(Style::Raw(pos, color.clone()));
+
+    let mut rest = input;
+    while let Some(start) = rest.find(ESCAPE) {
+        write!(out, "{}", &rest[..start])?;
+        rest = &rest[start..];
+
+        let len = sequence_len(rest);
+        if let Some(params) = rest[..len]
+            .strip_prefix("\x1B[")
+            .and_then(|seq| seq.strip_suffix('m'))
+        {
+            apply_sgr(&mut color, params);
+            out.set_layer(outer);
+            out.push(Style::Raw(pos, color.clone()));
+        }
+        out.skip(len);
+        rest = &rest[len..];
+    }
+    write!(out, "{rest}")?;
+
+    Ok(())
+}
+
+/// The length of the escape sequence at the start of the text.
+///
+/// Unterminated sequences extend to the end of the text.
+fn sequence_len(text: &str) -> usize {
+    let mut chars = text.char_indices().skip(1);
+    match chars.next() {
+        // Control sequence: parameters and intermediate bytes, terminated by a final byte.
+        Some((_, '[')) => chars
+            .find(|&(_, c)| !matches!(c, '\x20'..='\x3F'))
+            .map_or(text.len(), |(i, c)| i + c.len_utf8()),
+        // Operating system command: terminated by a bell or a string terminator.
+        Some((_, ']')) => match text[2..].find(['\x07', '\x1B']).map(|i| i + 2) {
+            Some(i) if text[i..].starts_with("\x1B\\") => i + 2,
+            Some(i) if text[i..].starts_with('\x07') => i + 1,
+            // Another sequence starts before this one was terminated.
+            Some(i) => i,
+            None => text.len(),
+        },
+        Some((i, c)) => i + c.len_utf8(),
+        None => text.len(),
+    }
+}
+
+/// Apply the parameters of an SGR sequence to the color.
+fn apply_sgr(color: &mut ColorSpec, params: &str) {
+    // An empty parameter is a reset, while invalid ones are skipped.
+    let mut params = params.split([';', ':']).map(|param| match param {
+        "" => Some(0),
+        param => param.parse::<u8>().ok(),
+    });
+    while let Some(param) = params.next() {
+        let Some(param) = param else {
+            continue;
+        };
+        if param == 0 {
+            *color = ColorSpec::new();
+            continue;
+        }
+        match param {
+            1 => color.set_bold(true),
+            2 => color.set_dimmed(true),
+            3 => color.set_italic(true),
+            4 => color.set_underline(true),
+            9 => color.set_strikethrough(true),
+            21 | 22 => color.set_bold(false).set_dimmed(false),
+            23 => color.set_italic(false),
+            24 => color.set_underline(false),
+            29 => color.set_strikethrough(false),
+            30..=37 => color.set_fg(Some(basic_color(param - 30))),
+            38 => match extended_color(&mut params) {
+                Some(fg) => color.set_fg(Some(fg)),
+                None => color,
+            },
+            39 => color.set_fg(None),
+            40..=47 => color.set_bg(Some(basic_color(param - 40))),
+            48 => match extended_color(&mut params) {
+                Some(bg) => color.set_bg(Some(bg)),
+                None => color,
+            },
+            49 => color.set_bg(None),
+            // The intense flag would apply to both colors, so use the bright ones of the palette.
+            90..=97 => color.set_fg(Some(Color::Ansi256(8 + param - 90))),
+            100..=107 => color.set_bg(Some(Color::Ansi256(8 + param - 100))),
+            _ => color,
+        };
+    }
+}
+
+fn basic_color(index: u8) -> Color {
+    match index {
+        0 => Color::Black,
+        1 => Color::Red,
+        2 => Color::Green,
+        3 => Color::Yellow,
+        4 => Color::Blue,
+        5 => Color::Magenta,
+        6 => Color::Cyan,
+        _ => Color::White,
+    }
+}
+
+/// Read the color of a `38` or `48` parameter, i.e. either `5;INDEX` or `2;R;G;B`.
+///
+/// Returns `None` if the color is incomplete or a component does not fit into a byte,
+/// after consuming all of its components.
+fn extended_color(params: &mut impl Iterator<Item = Option<u8>>) -> Option<Color> {
+    match params.next()?? {
+        5 => Some(Color::Ansi256(params.next()??)),
+        2 => {
+            let [r, g, b] = [params.next()?, params.next()?, params.next()?];
+            Some(Color::Rgb(r?, g?, b?))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sgr(params: &str) -> ColorSpec {
+        let mut color = ColorSpec::new();
+        apply_sgr(&mut color, params);
+        color
+    }
+
+    #[test]
+    fn test_sequence_len() {
+        assert_eq!(sequence_len("\x1B[31mred"), 5);
+        assert_eq!(sequence_len("\x1B[38;2;1;2;3mx"), 13);
+        assert_eq!(sequence_len("\x1B[?25hx"), 6);
+        assert_eq!(sequence_len("\x1B]0;title\x07x"), 10);
+        assert_eq!(sequence_len("\x1B]8;;url\x1B\\x"), 10);
+        // Another sequence starts before the command is terminated.
+        assert_eq!(sequence_len("\x1B]0;title\x1B[31m"), 9);
+        assert_eq!(sequence_len("\x1B(Bx"), 2);
+        assert_eq!(sequence_len("\x1Bé"), 3);
+        // Unterminated sequences.
+        assert_eq!(sequence_len("\x1B[31"), 4);
+        assert_eq!(sequence_len("\x1B]0;title"), 9);
+        assert_eq!(sequence_len("\x1B"), 1);
+    }
+
+    #[test]
+    fn test_apply_sgr_colors() {
+        assert_eq!(sgr("31"), *ColorSpec::new().set_fg(Some(Color::Red)));
+        assert_eq!(sgr("42"), *ColorSpec::new().set_bg(Some(Color::Green)));
+        assert_eq!(
+            sgr("38;5;208"),
+            *ColorSpec::new().set_fg(Some(Color::Ansi256(208)))
+        );
+        assert_eq!(
+            sgr("48;5;17"),
+            *ColorSpec::new().set_bg(Some(Color::Ansi256(17)))
+        );
+        assert_eq!(
+            sgr("38;2;1;2;3"),
+            *ColorSpec::new().set_fg(Some(Color::Rgb(1, 2, 3)))
+        );
+        assert_eq!(
+            sgr("38:2:1:2:3"),
+            *ColorSpec::new().set_fg(Some(Color::Rgb(1, 2, 3)))
+        );
+        assert_eq!(
+            sgr("48;2;4;5;6"),
+            *ColorSpec::new().set_bg(Some(Color::Rgb(4, 5, 6)))
+        );
+        // Incomplete extended colors are ignored.
+        assert_eq!(sgr("38;5"), ColorSpec::new());
+        assert_eq!(sgr("38;2;1;2"), ColorSpec::new());
+    }
+
+    #[test]
+    fn test_apply_sgr_invalid() {
+        let mut red = ColorSpec::new();
+        red.set_fg(Some(Color::Red));
+        // Invalid parameters are skipped instead of resetting.
+        assert_eq!(sgr("31;300"), red);
+        assert_eq!(sgr("31;x;1"), *red.clone().set_bold(true));
+        // Empty parameters still reset.
+        assert_eq!(sgr("31;"), ColorSpec::new());
+        assert_eq!(sgr(""), ColorSpec::new());
+
+        // Extended colors with a component that doesn't fit are dropped, along with the component.
+        assert_eq!(sgr("31;38;5;300"), red);
+        assert_eq!(sgr("38;5;300;1"), *ColorSpec::new().set_bold(true));
+        assert_eq!(sgr("31;38;2;1;256;3;4"), *red.clone().set_underline(true));
+        assert_eq!(sgr("48;2;1;2;999"), ColorSpec::new());
+        assert_eq!(sgr("38;300;1"), *ColorSpec::new().set_bold(true));
+    }
+
+    #[test]
+    fn test_apply_sgr_bright_colors() {
+        let mut expected = ColorSpec::new();
+        expected
+            .set_fg(Some(Color::Ansi256(9)))
+            .set_bg(Some(Color::Green));
+        assert_eq!(sgr("91;42"), expected);
+        expected
+            .set_fg(Some(Color::Red))
+            .set_bg(Some(Color::Ansi256(9)));
+        assert_eq!(sgr("31;101"), expected);
+        assert_eq!(
+            sgr("97"),
+            *ColorSpec::new().set_fg(Some(Color::Ansi256(15)))
+        );
+        assert_eq!(
+            sgr("100"),
+            *ColorSpec::new().set_bg(Some(Color::Ansi256(8)))
+        );
+    }
+
+    #[test]
+    fn test_apply_sgr_attributes_and_resets() {
+        let mut expected = ColorSpec::new();
+        expected
+            .set_bold(true)
+            .set_italic(true)
+            .set_underline(true)
+            .set_fg(Some(Color::Blue));
+        assert_eq!(sgr("1;3;4;34"), expected);
+
+        let mut color = expected.clone();
+        apply_sgr(&mut color, "22;23;39");
+        assert_eq!(color, *ColorSpec::new().set_underline(true));
+        apply_sgr(&mut color, "24");
+        assert_eq!(color, ColorSpec::new());
+
+        let mut color = expected.clone();
+        apply_sgr(&mut color, "0;32");
+        assert_eq!(color, *ColorSpec::new().set_fg(Some(Color::Green)));
+        // An empty parameter list resets everything as well.
+        apply_sgr(&mut color, "");
+        assert_eq!(color, ColorSpec::new());
+    }
+}
@@ -0,0 +1,249 @@
+//! Interoperability with [`anstyle`].
+//!
+//! ```
+//! # use typst_ansi_hl::Highlighter;
+//! let mut out = Vec::new();
+//! Highlighter::default().highlight_to_anstyle("This is _Typst_ #underline[code].", &mut out)?;
+//! # Ok::<(), typst_ansi_hl::Error>(())
+//! ```
+use std::io::Write;
+
+use anstyle::{Ansi256Color, AnsiColor, Effects, RgbColor, Style};
+use termcolor::{Color, ColorSpec, HyperlinkSpec, WriteColor};
+
+use crate::{Error, Highlighter};
+
+impl Highlighter {
+    /// Highlight Typst code and write it to the given output, rendering styles with [`anstyle`].
+    ///
+    /// Any writer can be used, e.g. an [`anstream`] stream,
+    /// which adapts the escape sequences to what the terminal supports.
+    ///
+    /// [`anstream`]: https://docs.rs/anstream
+    pub fn highlight_to_anstyle<W: Write>(&self, input: &str, out: W) -> Result<(), Error> {
+        self.highlight_to(input, AnstyleWriter::new(out))
+    }
+}
+
+/// Convert a [`ColorSpec`] into an [`anstyle`] style.
+pub fn to_anstyle(spec: &ColorSpec) -> Style {
+    let effects = [
+        (spec.bold(), Effects::BOLD),
+        (spec.dimmed(), Effects::DIMMED),
+        (spec.italic(), Effects::ITALIC),
+        (spec.underline(), Effects::UNDERLINE),
+        (spec.strikethrough(), Effects::STRIKETHROUGH),
+    ]
+    .into_iter()
+    .fold(Effects::new(), |effects, (enabled, effect)| {
+        effects.set(effect, enabled)
+    });
+
+    Style::new()
+        .fg_color(
+            spec.fg()
+                .and_then(|&fg| to_anstyle_color(fg, spec.intense())),
+        )
+        .bg_color(
+            spec.bg()
+                .and_then(|&bg| to_anstyle_color(bg, spec.intense())),
+        )
+        .effects(effects)
+}
+
+/// Convert an [`anstyle`] style into a [`ColorSpec`].
+///
+/// Effects without an equivalent, e.g. blinking, are dropped.
+/// Bright colors become the bright colors of the 256-color palette.
+pub fn from_anstyle(style: Style) -> ColorSpec {
+    let effects = style.get_effects();
+
+    let mut spec = ColorSpec::new();
+    spec.set_fg(style.get_fg_color().map(from_anstyle_color))
+        .set_bg(style.get_bg_color().map(from_anstyle_color))
+        .set_bold(effects.contains(Effects::BOLD))
+        .set_dimmed(effects.contains(Effects::DIMMED))
+        .set_italic(effects.contains(Effects::ITALIC))
+        .set_underline(effects.contains(Effects::UNDERLINE))
+        .set_strikethrough(effects.contains(Effects::STRIKETHROUGH));
+    spec
+}
+
+fn to_anstyle_color(color: Color, intense: bool) -> Option<anstyle::Color> {
+    let ansi = match color {
+        Color::Black => AnsiColor::Black,
+        Color::Red => AnsiColor::Red,
+        Color::Green => AnsiColor::Green,
+        Color::Yellow => AnsiColor::Yellow,
+        Color::Blue => AnsiColor::Blue,
+        Color::Magenta => AnsiColor::Magenta,
+        Color::Cyan => AnsiColor::Cyan,
+        Color::White => AnsiColor::White,
+        // The bright colors of the palette are what `from_anstyle` turns bright colors into.
+        Color::Ansi256(index @ 8..16) => {
+            return Ansi256Color(index).into_ansi().map(Into::into);
+        }
+        Color::Ansi256(index) => return Some(Ansi256Color(index).into()),
+        Color::Rgb(r, g, b) => return Some(RgbColor(r, g, b).into()),
+        _ => return None,
+    };
+    Some(ansi.bright(intense).into())
+}
+
+fn from_anstyle_color(color: anstyle::Color) -> Color {
+    match color {
+        // The intense flag would apply to both colors, so use the bright ones of the palette.
+        anstyle::Color::Ansi(ansi) if ansi.is_bright() => {
+            Color::Ansi256(Ansi256Color::from_ansi(ansi).index())
+        }
+        anstyle::Color::Ansi(ansi) => match ansi {
+            AnsiColor::Black => Color::Black,
+            AnsiColor::Red => Color::Red,
+            AnsiColor::Green => Color::Green,
+            AnsiColor::Yellow => Color::Yellow,
+            AnsiColor::Blue => Color::Blue,
+            AnsiColor::Magenta => Color::Magenta,
+            AnsiColor::Cyan => Color::Cyan,
+            _ => Color::White,
+        },
+        anstyle::Color::Ansi256(Ansi256Color(index)) => Color::Ansi256(index),
+        anstyle::Color::Rgb(RgbColor(r, g, b)) => Color::Rgb(r, g, b),
+    }
+}
+
+/// A writer that renders colors using [`anstyle`].
+struct AnstyleWriter<W> {
+    inner: W,
+    current: Style,
+}
+
+impl<W> AnstyleWriter<W> {
+    fn new(inner: W) -> AnstyleWriter<W> {
+        AnstyleWriter {
+            inner,
+            current: Style::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for AnstyleWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> WriteColor for AnstyleWriter<W> {
+    fn supports_color(&self) -> bool {
+        true
+    }
+
+    fn set_color(&mut self, spec: &ColorSpec) -> std::io::Result<()> {
+        let style = to_anstyle(spec);
+        write!(
+            self.inner,
+            "{}{}",
+            self.current.render_reset(),
+            style.render()
+        )?;
+        self.current = style;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> std::io::Result<()> {
+        write!(self.inner, "{}", self.current.render_reset())?;
+        self.current = Style::new();
+        Ok(())
+    }
+
+    fn set_hyperlink(&mut self, _link: &HyperlinkSpec) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn supports_hyperlinks(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_anstyle_roundtrip() {
+        let colors = [
+            Color::Black,
+            Color::Red,
+            Color::White,
+            Color::Ansi256(9),
+            Color::Ansi256(42),
+            Color::Rgb(1, 2, 3),
+        ];
+        for fg in colors {
+            for bg in colors {
+                let mut spec = ColorSpec::new();
+                spec.set_fg(Some(fg))
+                    .set_bg(Some(bg))
+                    .set_bold(true)
+                    .set_underline(true);
+                assert_eq!(from_anstyle(to_anstyle(&spec)), spec);
+            }
+        }
+    }
+
+    #[test]
+    fn test_from_anstyle_roundtrip() {
+        let colors: [anstyle::Color; 6] = [
+            AnsiColor::Blue.into(),
+            AnsiColor::BrightBlue.into(),
+            AnsiColor::BrightWhite.into(),
+            Ansi256Color(200).into(),
+            RgbColor(4, 5, 6).into(),
+            AnsiColor::Black.into(),
+        ];
+        for fg in colors {
+            for bg in colors {
+                let style = Style::new()
+                    .fg_color(Some(fg))
+                    .bg_color(Some(bg))
+                    .effects(Effects::ITALIC | Effects::DIMMED);
+                assert_eq!(to_anstyle(&from_anstyle(style)), style);
+            }
+        }
+    }
+
+    #[test]
+    fn test_from_anstyle_bright() {
+        let style = Style::new()
+            .fg_color(Some(AnsiColor::BrightRed.into()))
+            .bg_color(Some(AnsiColor::Blue.into()));
+        let spec = from_anstyle(style);
+        assert_eq!(spec.fg(), Some(&Color::Ansi256(9)));
+        assert_eq!(spec.bg(), Some(&Color::Blue));
+        assert!(!spec.intense());
+
+        let mut intense = ColorSpec::new();
+        intense.set_fg(Some(Color::Red)).set_intense(true);
+        assert_eq!(
+            to_anstyle(&intense),
+            Style::new().fg_color(Some(AnsiColor::BrightRed.into()))
+        );
+    }
+
+    #[test]
+    fn test_highlight_to_anstyle() {
+        let mut out = Vec::new();
+        Highlighter::default()
+            .highlight_to_anstyle("= A\n*b* #f(1)", &mut out)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\x1B[1m\x1B[36m= A\x1B[0m\n\
+             \x1B[1m\x1B[33m*b*\x1B[0m \
+             \x1B[3m\x1B[34m#f\x1B[0m(\x1B[33m1\x1B[0m)"
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use typst_syntax::LinkedNode;
+
+use crate::{annotate::Annotated, fit::Plan, Error, HighlightLevel, Highlighter, SyntaxMode};
+
+/// A part of a message that shares a size budget with the other parts.
+///
+/// See [`Highlighter::highlight_parts`].
+#[derive(Debug, Clone, Copy)]
+pub enum MessagePart<'a> {
+    /// Text that is kept as is, like prose around the code.
+    Text(&'a str),
+    /// Typst code that is highlighted in the given syntax mode.
+    Code(&'a str, SyntaxMode),
+}
+
+impl Highlighter {
+    /// Highlight several snippets of Typst code along with surrounding text,
+    /// so that the whole message stays below a shared budget.
+    ///
+    /// The budget is measured in the [size unit](Highlighter::with_size_unit)
+    /// and includes the text.
+    ///
+    /// Less colors are used as long as the message exceeds the budget,
+    /// always taking them from the snippet with the most highlighting left,
+    /// so that the colors are distributed evenly across the snippets.
+    /// The snippet that is degraded last only loses as much as needed.
+    /// If even no highlighting exceeds the budget, the message is returned anyway.
+    ///
+    /// The parts are concatenated in order.
+    /// The soft and the hard limit of the highlighter are not applied.
+    ///
+    /// ```
+    /// # use typst_ansi_hl::{Highlighter, MessagePart, SyntaxMode};
+    /// let mut highlighter = Highlighter::default();
+    /// highlighter.for_discord();
+    /// let parts = [
+    ///     MessagePart::Text("Try this:\n"),
+    ///     MessagePart::Code("= Title\nSome *strong* text.", SyntaxMode::Markup),
+    ///     MessagePart::Text("or this:\n"),
+    ///     MessagePart::Code("calc.pow(2, 8)", SyntaxMode::Code),
+    /// ];
+    /// let full = highlighter.highlight_parts(&parts, usize::MAX)?;
+    /// let message = highlighter.highlight_parts(&parts, 130)?;
+    /// assert!(message.starts_with("Try this:\n```ansi\n"));
+    /// assert!(full.chars().count() >= 130);
+    /// assert!(message.chars().count() < 130);
+    /// // Only some of the colors had to go.
+    /// assert!(message.contains('\x1b'));
+    /// # Ok::<(), typst_ansi_hl::Error>(())
+    /// ```
+    pub fn highlight_parts(&self, parts: &[MessagePart], budget: usize) -> Result<String, Error> {
+        let unit = self.size_unit();
+        let mut text_size = 0;
+        let mut snippets: Vec<(Annotated, Plan)> = Vec::new();
+        for part in parts {
+            match part {
+                MessagePart::Text(text) => text_size += unit.measure(text),
+                MessagePart::Code(input, mode) => {
+                    let parsed = mode.parse(input);
+                    let annotated = self.annotate(&LinkedNode::new(&parsed), None, &[], None)?;
+                    snippets.push((annotated, Plan::new(self.level)));
+                }
+            }
+        }
+
+        let total = |snippets: &[(Annotated, Plan)]| {
+            let sizes = snippets
+                .iter()
+                .map(|(annotated, plan)| self.measure(annotated, plan));
+            text_size + sizes.sum::<usize>()
+        };
+        let mut last = None;
+        while total(&snippets) >= budget {
+            // Lower the snippet with the most highlighting, or among them the one saving the most.
+            let lowered = snippets
+                .iter()
+                .enumerate()
+                .filter(|(_, (_, plan))| plan.level != HighlightLevel::Off)
+                .max_by_key(|(_, (annotated, plan))| {
+                    let lower = Plan::new(plan.level.restrict());
+                    let saving = self
+                        .measure(annotated, plan)
+                        .saturating_sub(self.measure(annotated, &lower));
+                    (plan.level, saving)
+                });
+            let Some((i, _)) = lowered else {
+                break;
+            };
+            let plan = &mut snippets[i].1;
+            *plan = Plan::new(plan.level.restrict());
+            last = Some(i);
+        }
+
+        // Only degrade as much of the last snippet as needed.
+        if let Some(i) = last {
+            let (annotated, plan) = &snippets[i];
+            let others = total(&snippets) - self.measure(annotated, plan);
+            if let Some(remaining) = budget.checked_sub(others) {
+                snippets[i].1 = self.fit_plan(annotated, remaining);
+            }
+        }
+
+        let mut out = String::new();
+        let mut snippets = snippets.iter();
+        for part in parts {
+            match part {
+                MessagePart::Text(text) => out.push_str(text),
+                MessagePart::Code(..) => {
+                    let (annotated, plan) = snippets.next().expect("every snippet is highlighted");
+                    out += &self.render(annotated, plan)?;
+                }
+            }
+        }
+        Ok(out)
+    }
+}
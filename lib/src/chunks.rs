@@ -0,0 +1,290 @@
+use std::ops::Range;
+
+use typst_syntax::{LinkedNode, SyntaxNode};
+
+use crate::{annotate::Annotator, fit::Plan, truncate::Truncation, Error, Highlighter};
+
+/// The minimum size of the source text highlighted per chunk.
+const CHUNK_SIZE: usize = 4096;
+
+/// An iterator over the highlighted output, produced chunk by chunk.
+///
+/// Created by [`Highlighter::highlight_chunks`].
+#[derive(Debug)]
+pub struct HighlightChunks<'a> {
+    highlighter: &'a Highlighter,
+    root: SyntaxNode,
+    ends_with_newline: bool,
+    plan: Option<Plan>,
+    elided: Vec<Range<usize>>,
+    truncation: Option<Truncation>,
+    ranges: std::vec::IntoIter<Range<usize>>,
+    state: ChunkState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChunkState {
+    Opening,
+    Body,
+    Marker,
+    Closing,
+    Done,
+}
+
+impl Highlighter {
+    /// Highlight Typst code chunk by chunk.
+    ///
+    /// The input is split at top-level nodes, so that only one part of it needs to be
+    /// highlighted at a time, e.g. between writes to an asynchronous output.
+    /// The concatenated chunks look like the output of [`Highlighter::highlight`].
+    /// Each chunk resets its colors at its end.
+    ///
+    /// If a soft or a hard limit is set, the output is measured once before the first chunk is produced.
+    /// Regions elided to fit the limits are elided in the chunks as well,
+    /// and chunks after the cut of a hard limit are left out.
+    ///
+    /// ```
+    /// # use typst_ansi_hl::Highlighter;
+    /// let mut output = String::new();
+    /// for chunk in Highlighter::default().highlight_chunks("This is _Typst_ #underline[code].") {
+    ///     output.push_str(&chunk?);
+    /// }
+    /// # Ok::<(), typst_ansi_hl::Error>(())
+    /// ```
+    pub fn highlight_chunks<'a>(&'a self, input: &str) -> HighlightChunks<'a> {
+        let root = self.parse(input);
+
+        let mut ranges = Vec::new();
+        let mut start = 0;
+        let mut end = 0;
+        for child in root.children() {
+            end += child.len();
+            if end - start >= CHUNK_SIZE {
+                ranges.push(start..end);
+                start = end;
+            }
+        }
+        if start < end || ranges.is_empty() {
+            ranges.push(start..end);
+        }
+
+        HighlightChunks {
+            highlighter: self,
+            root,
+            ends_with_newline: input.ends_with('\n'),
+            plan: None,
+            elided: Vec::new(),
+            truncation: None,
+            ranges: ranges.into_iter(),
+            state: ChunkState::Opening,
+        }
+    }
+
+    /// Highlight Typst code and write it to the given asynchronous output.
+    ///
+    /// The output is highlighted and written chunk by chunk.
+    /// See [`Highlighter::highlight_chunks`].
+    #[cfg(feature = "tokio")]
+    pub async fn highlight_to_async<W>(&self, input: &str, mut out: W) -> Result<(), Error>
+    where
+        W: tokio::io::AsyncWrite + Unpin,
+    {
+        use tokio::io::AsyncWriteExt;
+
+        for chunk in self.highlight_chunks(input) {
+            out.write_all(chunk?.as_bytes()).await?;
+        }
+        out.flush().await?;
+        Ok(())
+    }
+}
+
+impl HighlightChunks<'_> {
+    fn plan(&mut self) -> Result<Plan, Error> {
+        if let Some(plan) = &self.plan {
+            return Ok(plan.clone());
+        }
+        let plan = match self.highlighter.fit_limit() {
+            Some(_) => {
+                let fitted = self.highlighter.fit(&LinkedNode::new(&self.root), None)?;
+                self.elided = fitted.elided;
+                self.truncation = fitted.truncation;
+                fitted.plan
+            }
+            None => Plan::new(self.highlighter.level),
+        };
+        self.plan = Some(plan.clone());
+        Ok(plan)
+    }
+
+    fn render(&self, plan: &Plan, range: Range<usize>) -> Result<String, Error> {
+        let linked = LinkedNode::new(&self.root);
+        let mut annotator = Annotator::new(0)
+            .with_range(range)
+            .with_elided(&self.elided);
+        self.highlighter.write_node(&linked, &mut annotator)?;
+        self.highlighter.render(&annotator.finish(), plan)
+    }
+
+    fn render_marker(&self, plan: &Plan, marker: &str) -> Result<String, Error> {
+        let mut annotator = Annotator::new(0);
+        annotator.write_marker(marker);
+        self.highlighter.render(&annotator.finish(), plan)
+    }
+}
+
+impl Iterator for HighlightChunks<'_> {
+    type Item = Result<String, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.state == ChunkState::Done {
+            return None;
+        }
+        let plan = match self.plan() {
+            Ok(plan) => plan,
+            Err(err) => {
+                self.state = ChunkState::Done;
+                return Some(Err(err));
+            }
+        };
+
+        loop {
+            match self.state {
+                ChunkState::Opening => {
+                    self.state = ChunkState::Body;
+                    if self.highlighter.discord {
+                        return Some(Ok("```ansi\n".to_string()));
+                    }
+                }
+                ChunkState::Body => match (self.ranges.next(), &self.truncation) {
+                    (Some(range), None) => return Some(self.render(&plan, range)),
+                    (Some(range), Some(truncation)) if range.start < truncation.cut => {
+                        let end = range.end.min(truncation.cut);
+                        return Some(self.render(&plan, range.start..end));
+                    }
+                    (Some(_), Some(_)) => {}
+                    (None, _) => self.state = ChunkState::Marker,
+                },
+                ChunkState::Marker => {
+                    self.state = ChunkState::Closing;
+                    if let Some(truncation) = &self.truncation {
+                        if !truncation.marker.is_empty() {
+                            return Some(self.render_marker(&plan, &truncation.marker));
+                        }
+                    }
+                }
+                ChunkState::Closing => {
+                    self.state = ChunkState::Done;
+                    if self.highlighter.discord {
+                        // Make sure that the closing fences are on their own line.
+                        let at_line_start = match &self.truncation {
+                            Some(truncation) if !truncation.marker.is_empty() => {
+                                truncation.marker.ends_with('\n')
+                            }
+                            _ => self.ends_with_newline,
+                        };
+                        let newline = if at_line_start { "" } else { "\n" };
+                        return Some(Ok(format!("{newline}```\n")));
+                    }
+                }
+                ChunkState::Done => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Elision;
+
+    fn input() -> String {
+        let section = "= Section\n#let f(x) = [*strong* _emph_ #x, with some more text]\n\
+            Some text with $x^2 + 1$ and `raw` in it. // comment\n\
+            ```rust\nfn main() {}\n```\n\n";
+        let input = section.repeat(3 * CHUNK_SIZE / section.len());
+        assert!(input.len() > 2 * CHUNK_SIZE);
+        input
+    }
+
+    fn chunks(highlighter: &Highlighter, input: &str) -> String {
+        let chunks = highlighter.highlight_chunks(input);
+        chunks.collect::<Result<Vec<_>, _>>().unwrap().concat()
+    }
+
+    #[test]
+    fn test_chunks_equal_output() {
+        let input = input();
+        let plain = input.len();
+
+        for discord in [false, true] {
+            let mut highlighter = Highlighter::default();
+            if discord {
+                highlighter.for_discord();
+            }
+            let expected = highlighter.highlight(&input).unwrap();
+            let full = expected.len();
+            assert!(highlighter.highlight_chunks(&input).count() > 2);
+            assert_eq!(chunks(&highlighter, &input), expected);
+
+            let mut soft = highlighter.clone();
+            soft.with_soft_limit((plain + full) / 2);
+            let expected = soft.highlight(&input).unwrap();
+            assert!(expected.chars().count() < (plain + full) / 2);
+            assert!(expected.contains('\x1b'));
+            assert_eq!(chunks(&soft, &input), expected);
+
+            let mut hard = highlighter.clone();
+            hard.with_hard_limit(plain / 2);
+            let expected = hard.highlight(&input).unwrap();
+            assert!(expected.contains(" more lines"));
+            assert_eq!(chunks(&hard, &input), expected);
+
+            let mut elided = highlighter.clone();
+            elided
+                .with_soft_limit(plain / 2)
+                .with_hard_limit(plain / 3)
+                .with_elision(Elision::BeforeColors);
+            let expected = elided.highlight(&input).unwrap();
+            assert!(expected.contains('…') && expected.contains(" more lines"));
+            assert_eq!(chunks(&elided, &input), expected);
+        }
+    }
+
+    #[test]
+    fn test_chunks_small_input() {
+        let highlighter = Highlighter::default().for_discord().clone();
+        for input in ["", "text", "#let x = 1\n"] {
+            let expected = highlighter.highlight(input).unwrap();
+            assert_eq!(chunks(&highlighter, input), expected);
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_highlight_to_async() {
+        let input = input();
+        let mut highlighter = Highlighter::default();
+        highlighter.for_discord();
+        let mut hard = highlighter.clone();
+        hard.with_hard_limit(input.len() / 2);
+
+        for highlighter in [highlighter, hard] {
+            let mut out = Vec::new();
+            highlighter
+                .highlight_to_async(&input, &mut out)
+                .await
+                .unwrap();
+            assert_eq!(out, highlighter.highlight(&input).unwrap().into_bytes());
+        }
+
+        // The output does not fit into the buffer.
+        let mut buffer = [0; 64];
+        let out = std::io::Cursor::new(&mut buffer[..]);
+        let result = Highlighter::default().highlight_to_async(&input, out).await;
+        assert!(matches!(
+            result,
+            Err(Error::Io(err)) if err.kind() == std::io::ErrorKind::WriteZero
+        ));
+    }
+}
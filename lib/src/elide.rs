@@ -0,0 +1,162 @@
+use std::{cmp::Reverse, ops::Range};
+
+use typst_syntax::{LinkedNode, SyntaxKind};
+
+use crate::{
+    annotate::{Annotated, PLACEHOLDER},
+    fit::Plan,
+    Error, Highlighter,
+};
+
+/// The minimum size of a region in bytes for it to be elided.
+const MIN_ELIDED_SIZE: usize = 32;
+
+/// When to elide long regions of the code, like content blocks, strings,
+/// raw blocks and function bodies, in order to get below a size limit.
+///
+/// Elided regions are replaced by a placeholder, `…`, keeping their delimiters,
+/// so that the shape of the code is preserved.
+///
+/// See [`Highlighter::with_elision`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Elision {
+    /// Never elide anything.
+    #[default]
+    Off,
+    /// Elide regions before using less colors, so that the colors are kept.
+    BeforeColors,
+    /// Elide regions only once the output exceeds the limit even without highlighting.
+    ///
+    /// Afterwards, as many colors are used again as fit below the limit.
+    AfterColors,
+}
+
+impl Highlighter {
+    /// Elide long regions of the code until the output gets below the limit.
+    ///
+    /// Regions nested most deeply are elided first, and the largest ones among them.
+    /// Returns the output along with the ordered elided ranges.
+    pub(crate) fn elide(
+        &self,
+        node: &LinkedNode,
+        range: Option<Range<usize>>,
+        annotated: Annotated,
+        plan: &Plan,
+        limit: usize,
+    ) -> Result<(Annotated, Vec<Range<usize>>), Error> {
+        let size = self.measure(&annotated, plan);
+        if size < limit {
+            return Ok((annotated, Vec::new()));
+        }
+
+        let bounds = range.clone().unwrap_or_else(|| node.range());
+        let mut candidates = Vec::new();
+        collect_candidates(node, 0, &mut candidates);
+        candidates.retain(|(region, _)| {
+            bounds.start <= region.start
+                && region.end <= bounds.end
+                && region.len() >= MIN_ELIDED_SIZE
+        });
+        candidates.sort_by_key(|(region, depth)| (Reverse(*depth), Reverse(region.len())));
+
+        let unit = self.size_unit();
+        let text = node.get().clone().into_text();
+        let offset = node.offset();
+        // The placeholder is styled like a comment, so its escape sequences count as well.
+        let placeholder_size = self.measure_marker(PLACEHOLDER, plan);
+        let saving = |region: &Range<usize>| {
+            let elided = &text[region.start - offset..region.end - offset];
+            unit.measure(elided).saturating_sub(placeholder_size)
+        };
+
+        let mut annotated = annotated;
+        let mut elided: Vec<Range<usize>> = Vec::new();
+        let mut needed = size + 1 - limit;
+        let mut outdated = false;
+        for (region, _) in candidates {
+            let contains = |outer: &Range<usize>, inner: &Range<usize>| {
+                outer.start <= inner.start && inner.end <= outer.end
+            };
+            if elided.iter().any(|other| contains(other, &region)) {
+                continue;
+            }
+            elided.retain(|other| !contains(&region, other));
+            elided.push(region);
+            outdated = true;
+
+            // Only highlight again once the estimated savings suffice.
+            let estimate: usize = elided.iter().map(saving).sum();
+            if estimate < needed {
+                continue;
+            }
+            elided.sort_by_key(|region| region.start);
+            annotated = self.annotate(node, range.clone(), &elided, None)?;
+            outdated = false;
+            let size = self.measure(&annotated, plan);
+            if size < limit {
+                break;
+            }
+            needed = estimate + (size + 1 - limit);
+        }
+
+        if outdated {
+            elided.sort_by_key(|region| region.start);
+            annotated = self.annotate(node, range, &elided, None)?;
+        }
+        Ok((annotated, elided))
+    }
+}
+
+/// Collect the regions that can be elided along with how deeply they are nested.
+fn collect_candidates(node: &LinkedNode, depth: usize, out: &mut Vec<(Range<usize>, usize)>) {
+    let region = match node.kind() {
+        SyntaxKind::ContentBlock => Some(delimited(node)),
+        SyntaxKind::Closure => node
+            .children()
+            .last()
+            .filter(|body| body.kind() == SyntaxKind::CodeBlock)
+            .map(|body| delimited(&body)),
+        SyntaxKind::Str if node.len() >= 2 => Some(node.offset() + 1..node.range().end - 1),
+        SyntaxKind::Raw => {
+            let is_fence = |child: &LinkedNode| {
+                matches!(
+                    child.kind(),
+                    SyntaxKind::RawDelim | SyntaxKind::RawLang | SyntaxKind::RawTrimmed
+                )
+            };
+            let mut content = node.children().filter(|child| !is_fence(child));
+            content.next().map(|first| {
+                let last = content.last().unwrap_or_else(|| first.clone());
+                first.offset()..last.range().end
+            })
+        }
+        _ => None,
+    };
+    if let Some(region) = region.filter(|region| !region.is_empty()) {
+        out.push((region, depth));
+    }
+
+    for child in node.children() {
+        collect_candidates(&child, depth + 1, out);
+    }
+}
+
+/// The range between the opening and the closing delimiter of a block.
+fn delimited(node: &LinkedNode) -> Range<usize> {
+    let start = node
+        .children()
+        .next()
+        .map_or(node.offset(), |open| open.range().end);
+    let end = match node.children().last() {
+        Some(close)
+            if matches!(
+                close.kind(),
+                SyntaxKind::RightBracket | SyntaxKind::RightBrace
+            ) =>
+        {
+            close.offset()
+        }
+        _ => node.range().end,
+    };
+    start..end.max(start)
+}
@@ -0,0 +1,440 @@
+use std::ops::Range;
+
+use typst_syntax::{LinkedNode, Tag};
+
+use crate::{
+    annotate::{Annotated, Annotator},
+    truncate::Truncation,
+    Elision, Error, HighlightLevel, Highlighter,
+};
+
+/// Which parts of the highlighting are used.
+///
+/// A plan is a highlight level, where some parts of the next lower level may already be degraded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Plan {
+    pub(crate) level: HighlightLevel,
+    /// Parts that are degraded in addition to the ones below the level.
+    pub(crate) degraded: Vec<Degradation>,
+}
+
+/// Highlighted output that was fitted to the size limits.
+pub(crate) struct Fitted {
+    pub(crate) annotated: Annotated,
+    pub(crate) plan: Plan,
+    /// The ordered source ranges that were elided.
+    pub(crate) elided: Vec<Range<usize>>,
+    pub(crate) truncation: Option<Truncation>,
+}
+
+/// A part of the highlighting that can be degraded on its own to get below the soft limit.
+///
+/// See [`HighlightReport::degradations`](crate::HighlightReport::degradations).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Degradation {
+    /// Styles like bold of all nodes with the tag.
+    Styles(Tag),
+    /// The color of all nodes with the tag.
+    Color(Tag),
+    /// The highlighted contents of the raw block at the byte position.
+    Raw(usize),
+}
+
+impl Plan {
+    pub(crate) fn new(level: HighlightLevel) -> Plan {
+        Plan {
+            level,
+            degraded: Vec::new(),
+        }
+    }
+
+    pub(crate) fn degrades(&self, degradation: Degradation) -> bool {
+        self.degraded.contains(&degradation)
+    }
+
+    /// Whether the contents of the raw block at the source position are highlighted.
+    pub(crate) fn highlights_raw(&self, pos: usize) -> bool {
+        self.level >= HighlightLevel::WithRaw && !self.degrades(Degradation::Raw(pos))
+    }
+}
+
+/// The lowest level at which nodes with the tag are colored by default.
+fn default_color_level(tag: Tag) -> HighlightLevel {
+    match tag {
+        Tag::Comment | Tag::Escape | Tag::Keyword | Tag::Error => HighlightLevel::L0,
+        Tag::Label | Tag::Ref | Tag::Number | Tag::String => HighlightLevel::L1,
+        Tag::Raw | Tag::Heading | Tag::ListMarker | Tag::ListTerm | Tag::MathOperator => {
+            HighlightLevel::L2
+        }
+        Tag::Punctuation
+        | Tag::Strong
+        | Tag::Emph
+        | Tag::Link
+        | Tag::MathDelimiter
+        | Tag::Operator
+        | Tag::Function
+        | Tag::Interpolated => HighlightLevel::L3,
+    }
+}
+
+/// Whether nodes with the tag use styles like bold.
+fn has_styles(tag: Tag) -> bool {
+    matches!(
+        tag,
+        Tag::Strong | Tag::Emph | Tag::Link | Tag::Label | Tag::Ref | Tag::Heading | Tag::Function
+    )
+}
+
+/// Find the index of the last cut at which the output still fits.
+///
+/// The search starts at the last cut at which the estimated size fits,
+/// which is only close to the actual size, so the cuts around it are tried as well.
+/// Returns `None` if not even the first cut fits.
+pub(crate) fn last_fitting<T>(
+    cuts: &[T],
+    estimate_fits: impl Fn(&T) -> bool,
+    mut fits: impl FnMut(usize) -> Result<bool, Error>,
+) -> Result<Option<usize>, Error> {
+    if cuts.is_empty() {
+        return Ok(None);
+    }
+    let estimated = cuts.partition_point(estimate_fits).saturating_sub(1);
+    if fits(estimated)? {
+        let mut i = estimated;
+        while i + 1 < cuts.len() && fits(i + 1)? {
+            i += 1;
+        }
+        return Ok(Some(i));
+    }
+    for i in (0..estimated).rev() {
+        if fits(i)? {
+            return Ok(Some(i));
+        }
+    }
+    Ok(None)
+}
+
+impl Highlighter {
+    /// Highlight a linked syntax node and fit the output to the size limits.
+    ///
+    /// If a limit is set, the output is measured first
+    /// and the highlighting is degraded until it gets below the limit.
+    /// If even no highlighting exceeds the limit, the output is kept anyway,
+    /// unless it exceeds the hard limit and is truncated.
+    pub(crate) fn fit(
+        &self,
+        node: &LinkedNode,
+        range: Option<Range<usize>>,
+    ) -> Result<Fitted, Error> {
+        let mut annotated = self.annotate(node, range.clone(), &[], None)?;
+        let Some(limit) = self.fit_limit() else {
+            return Ok(Fitted {
+                annotated,
+                plan: Plan::new(self.level),
+                elided: Vec::new(),
+                truncation: None,
+            });
+        };
+
+        let mut elided = Vec::new();
+        if self.elision == Elision::BeforeColors {
+            let plan = Plan::new(self.level);
+            (annotated, elided) = self.elide(node, range.clone(), annotated, &plan, limit)?;
+        }
+        let mut plan = self.fit_plan(&annotated, limit);
+        if self.elision == Elision::AfterColors {
+            (annotated, elided) = self.elide(node, range.clone(), annotated, &plan, limit)?;
+            // Eliding might leave room for some of the colors again.
+            if !elided.is_empty() {
+                plan = self.fit_plan(&annotated, limit);
+            }
+        }
+
+        let mut truncation = None;
+        let size = self.measure(&annotated, &plan);
+        if let Some(hard_limit) = self.hard_limit.filter(|&hard_limit| size >= hard_limit) {
+            let range = range.unwrap_or_else(|| node.range());
+            let truncated = self.truncate(node, range, &annotated, &plan, &elided, hard_limit)?;
+            (annotated, truncation) = (truncated.0, Some(truncated.1));
+        }
+
+        Ok(Fitted {
+            annotated,
+            plan,
+            elided,
+            truncation,
+        })
+    }
+
+    /// Find the plan with the most highlighting at which the output stays below the soft limit.
+    ///
+    /// Whole levels are dropped as long as the next lower level still exceeds the limit.
+    /// Then the parts of the last level are degraded one by one:
+    /// a part whose degradation alone gets below the limit is preferred, the smallest one of them,
+    /// otherwise the part that saves the most is degraded first.
+    pub(crate) fn fit_plan(&self, annotated: &Annotated, soft_limit: usize) -> Plan {
+        let mut level = self.level;
+        loop {
+            let mut plan = Plan::new(level);
+            let mut size = self.measure(annotated, &plan);
+            if size < soft_limit || level == HighlightLevel::Off {
+                return plan;
+            }
+
+            let next = level.restrict();
+            if self.measure(annotated, &Plan::new(next)) >= soft_limit {
+                level = next;
+                continue;
+            }
+
+            // The next level fits, so only degrade as much of this one as needed.
+            let mut savings = Vec::new();
+            for degradation in self.step_degradations(level, &annotated.raw_blocks) {
+                let mut degraded = plan.clone();
+                degraded.degraded.push(degradation);
+                let degraded_size = self.measure(annotated, &degraded);
+                if degraded_size < size {
+                    savings.push((degradation, size - degraded_size));
+                }
+            }
+            while !savings.is_empty() {
+                let excess = size + 1 - soft_limit;
+                let sufficient = savings
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &(_, saving))| saving >= excess)
+                    .min_by_key(|(_, &(_, saving))| saving);
+                let largest = || {
+                    savings
+                        .iter()
+                        .enumerate()
+                        .max_by_key(|(_, &(_, saving))| saving)
+                };
+                let Some((i, _)) = sufficient.or_else(largest) else {
+                    break;
+                };
+                let (degradation, _) = savings.remove(i);
+                plan.degraded.push(degradation);
+                size = self.measure(annotated, &plan);
+                if size < soft_limit {
+                    return plan;
+                }
+            }
+
+            return Plan::new(next);
+        }
+    }
+
+    /// The output size of the plan.
+    pub(crate) fn measure(&self, annotated: &Annotated, plan: &Plan) -> usize {
+        annotated.size(&self.resolve_styles(annotated, plan), self.size_unit())
+    }
+
+    /// The output size of a marker with the styles of the plan, including its escape sequences.
+    pub(crate) fn measure_marker(&self, marker: &str, plan: &Plan) -> usize {
+        let mut out = Annotator::new(0);
+        out.write_marker(marker);
+        self.measure(&out.finish(), plan)
+    }
+
+    /// The lowest level at which nodes with the tag are colored, if they are colored at all.
+    pub(crate) fn color_level(&self, tag: Tag) -> Option<HighlightLevel> {
+        match self.tag_levels.get(&tag) {
+            Some(&level) => level,
+            None => Some(default_color_level(tag)),
+        }
+    }
+
+    /// The parts that are degraded when going from the level to the next lower one.
+    fn step_degradations(&self, level: HighlightLevel, raw_blocks: &[usize]) -> Vec<Degradation> {
+        let mut degradations: Vec<Degradation> = Tag::LIST
+            .iter()
+            .copied()
+            .filter(|&tag| self.color_level(tag) == Some(level))
+            .map(Degradation::Color)
+            .collect();
+        match level {
+            HighlightLevel::WithStyles => degradations.extend(
+                Tag::LIST
+                    .iter()
+                    .copied()
+                    .filter(|&tag| has_styles(tag))
+                    .map(Degradation::Styles),
+            ),
+            HighlightLevel::WithRaw => {
+                degradations.extend(raw_blocks.iter().copied().map(Degradation::Raw))
+            }
+            _ => {}
+        }
+        degradations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use typst_syntax::LinkedNode;
+
+    use super::*;
+
+    const LEVELS: [HighlightLevel; 8] = [
+        HighlightLevel::Off,
+        HighlightLevel::L0,
+        HighlightLevel::L1,
+        HighlightLevel::L2,
+        HighlightLevel::L3,
+        HighlightLevel::WithRaw,
+        HighlightLevel::WithStyles,
+        HighlightLevel::All,
+    ];
+
+    const INPUT: &str =
+        "= Title\n#f(1) *a* *b* _c_ $x + y$ <label> \"s\" // c\n```rust\nlet x = 1;\n```\n";
+
+    fn annotate(highlighter: &Highlighter, input: &str) -> Annotated {
+        let parsed = highlighter.parse(input);
+        let linked = LinkedNode::new(&parsed);
+        highlighter.annotate(&linked, None, &[], None).unwrap()
+    }
+
+    /// How much degrading only the given part of the level saves.
+    fn saving(h: &Highlighter, annotated: &Annotated, degradation: Degradation) -> usize {
+        let level = HighlightLevel::L3;
+        let degraded = Plan {
+            level,
+            degraded: vec![degradation],
+        };
+        h.measure(annotated, &Plan::new(level)) - h.measure(annotated, &degraded)
+    }
+
+    #[test]
+    fn test_fit_plan_single_degradation() {
+        let h = Highlighter::default();
+        let annotated = annotate(&h, INPUT);
+        let l3 = h.measure(&annotated, &Plan::new(HighlightLevel::L3));
+        let strong = saving(&h, &annotated, Degradation::Color(Tag::Strong));
+        let function = saving(&h, &annotated, Degradation::Color(Tag::Function));
+        assert!(function < strong);
+
+        // Degrading functions alone suffices, and is the smallest part that does.
+        let plan = h.fit_plan(&annotated, l3 - function + 1);
+        assert_eq!(plan.level, HighlightLevel::L3);
+        assert_eq!(plan.degraded.len(), 1);
+        assert_eq!(h.measure(&annotated, &plan), l3 - function);
+    }
+
+    #[test]
+    fn test_fit_plan_several_degradations() {
+        let h = Highlighter::default();
+        let annotated = annotate(&h, INPUT);
+        let l3 = h.measure(&annotated, &Plan::new(HighlightLevel::L3));
+        let strong = saving(&h, &annotated, Degradation::Color(Tag::Strong));
+        let emph = saving(&h, &annotated, Degradation::Color(Tag::Emph));
+
+        // No single part suffices, so the largest one goes first, then the smallest sufficient one.
+        let limit = l3 - strong - 1;
+        let plan = h.fit_plan(&annotated, limit);
+        assert_eq!(plan.level, HighlightLevel::L3);
+        assert_eq!(plan.degraded.len(), 2);
+        assert_eq!(h.measure(&annotated, &plan), l3 - strong - emph);
+    }
+
+    #[test]
+    #[cfg(feature = "raw-highlighting")]
+    fn test_fit_plan_raw_blocks() {
+        let h = Highlighter::default();
+        let annotated = annotate(&h, INPUT);
+        let with_raw = h.measure(&annotated, &Plan::new(HighlightLevel::WithRaw));
+        let plan = h.fit_plan(&annotated, with_raw);
+        assert_eq!(plan.level, HighlightLevel::WithRaw);
+        assert_eq!(plan.degraded, [Degradation::Raw(annotated.raw_blocks[0])]);
+    }
+
+    #[test]
+    fn test_fit_plan_close_to_limit() {
+        let h = Highlighter::default();
+        let annotated = annotate(&h, INPUT);
+        let off = h.measure(&annotated, &Plan::new(HighlightLevel::Off));
+        let all = h.measure(&annotated, &Plan::new(HighlightLevel::All));
+        for limit in off + 1..=all + 1 {
+            let plan = h.fit_plan(&annotated, limit);
+            let size = h.measure(&annotated, &plan);
+            assert!(size < limit, "{limit}: {plan:?}");
+
+            // No higher level fits.
+            let i = LEVELS
+                .iter()
+                .position(|&level| level == plan.level)
+                .unwrap();
+            if let Some(&higher) = LEVELS.get(i + 1) {
+                assert!(h.measure(&annotated, &Plan::new(higher)) >= limit);
+            }
+            // It keeps at least as much as the next lower level.
+            assert!(size >= h.measure(&annotated, &Plan::new(plan.level.restrict())));
+            // Every degradation is needed.
+            for i in 0..plan.degraded.len() {
+                let mut undone = plan.clone();
+                undone.degraded.remove(i);
+                assert!(h.measure(&annotated, &undone) >= limit, "{limit}: {plan:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_fit_colors_after_elision() {
+        let input =
+            "#let f(x) = {\n  let y = x * 2\n  let z = (y, \"some long text\", 1, 2, 3)\n  \
+            let w = (z, \"even more text in here\", 4, 5, 6)\n  (y, z, w)\n}";
+        let mut h = Highlighter::default();
+        h.with_soft_limit(120).with_elision(Elision::AfterColors);
+        let parsed = h.parse(input);
+        let fitted = h.fit(&LinkedNode::new(&parsed), None).unwrap();
+        assert!(!fitted.elided.is_empty());
+        assert!(fitted.plan.level > HighlightLevel::Off);
+        assert!(h.measure(&fitted.annotated, &fitted.plan) < 120);
+
+        let output = h.highlight(input).unwrap();
+        assert!(output.contains('…') && output.contains('\x1b'));
+    }
+
+    #[test]
+    fn test_placeholder_follows_plan() {
+        let input = "#let f(x) = [Some long content that is going to be elided, #x]";
+        let mut h = Highlighter::default();
+        h.with_soft_limit(60).with_elision(Elision::BeforeColors);
+        let output = h.highlight(input).unwrap();
+        assert!(output.contains("\x1b[2m…\x1b[0m"), "{output:?}");
+
+        // Without colors, the placeholder is not colored either.
+        h.with_level(HighlightLevel::Off);
+        let output = h.highlight(input).unwrap();
+        assert_eq!(output, "#let f(x) = […]");
+    }
+
+    #[test]
+    fn test_tag_level_off() {
+        let input = "#let x = 1";
+        let keyword = "\x1b[35m#let\x1b[0m";
+
+        // Keywords at `Off` keep their color even once everything else has lost it.
+        let mut h = Highlighter::default();
+        h.with_tag_level(Tag::Keyword, HighlightLevel::Off);
+        let output = h.highlight(input).unwrap();
+        assert!(output.contains(keyword), "{output:?}");
+        for output in [
+            h.clone().with_soft_limit(1).highlight(input).unwrap(),
+            h.clone()
+                .with_level(HighlightLevel::Off)
+                .highlight(input)
+                .unwrap(),
+        ] {
+            assert!(output.contains(keyword), "{output:?}");
+            assert_eq!(output.matches("\x1b[3").count(), 1, "{output:?}");
+        }
+
+        // Without their color, keywords are plain while the rest is still colored.
+        h.without_tag_color(Tag::Keyword);
+        let output = h.highlight(input).unwrap();
+        assert!(!output.contains("\x1b[35m"), "{output:?}");
+        assert!(output.contains("\x1b[33m1"), "{output:?}");
+    }
+}
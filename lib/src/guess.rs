@@ -0,0 +1,357 @@
+use syntect::parsing::{SyntaxReference, SyntaxSet};
+
+/// The number of lines at the start and end of the text that are searched for modelines.
+const MODELINE_LINES: usize = 5;
+
+/// The hint score at which a guess is no longer penalized for little evidence.
+const CONFIDENT_SCORE: usize = 10;
+
+/// Interpreters whose name is not a language tag known to the built-in syntaxes.
+const INTERPRETERS: &[(&str, &str)] = &[("node", "js"), ("nodejs", "js"), ("deno", "ts")];
+
+/// Patterns that hint at a language, weighted by how characteristic they are.
+const HINTS: &[(&str, &[(&str, usize)])] = &[
+    (
+        "rs",
+        &[
+            ("fn ", 2),
+            ("let mut ", 3),
+            ("impl ", 3),
+            ("pub ", 2),
+            ("::", 1),
+            ("-> ", 1),
+            ("#[", 3),
+            ("println!", 4),
+            ("&mut ", 3),
+            ("match ", 2),
+        ],
+    ),
+    (
+        "py",
+        &[
+            ("def ", 3),
+            ("import ", 1),
+            ("elif ", 4),
+            ("self.", 2),
+            ("__", 2),
+            ("None", 2),
+            ("lambda ", 3),
+            (":\n", 1),
+        ],
+    ),
+    (
+        "js",
+        &[
+            ("function", 3),
+            ("const ", 2),
+            ("=> ", 1),
+            ("console.", 4),
+            ("===", 4),
+            ("require(", 4),
+            ("document.", 4),
+            ("undefined", 3),
+        ],
+    ),
+    (
+        "c",
+        &[
+            ("#include", 4),
+            ("int main", 4),
+            ("printf(", 3),
+            ("NULL", 3),
+            ("void ", 2),
+            ("struct ", 2),
+            ("char *", 3),
+            ("sizeof", 3),
+        ],
+    ),
+    (
+        "cpp",
+        &[
+            ("#include", 3),
+            ("std::", 5),
+            ("cout", 4),
+            ("template<", 4),
+            ("namespace ", 3),
+            ("nullptr", 4),
+        ],
+    ),
+    (
+        "java",
+        &[
+            ("public class", 5),
+            ("public static void", 5),
+            ("System.out", 5),
+            ("private ", 2),
+            ("import java", 5),
+            ("@Override", 5),
+        ],
+    ),
+    (
+        "go",
+        &[
+            ("package ", 3),
+            ("func ", 4),
+            (":= ", 4),
+            ("fmt.", 4),
+            ("defer ", 4),
+            ("chan ", 3),
+        ],
+    ),
+    (
+        "bash",
+        &[
+            ("echo ", 3),
+            ("fi\n", 3),
+            ("; then", 4),
+            ("$(", 3),
+            ("${", 2),
+            ("esac", 5),
+            ("; do", 4),
+            ("sudo ", 3),
+        ],
+    ),
+    ("json", &[("\": ", 3), ("\":", 1), ("},", 1), ("null", 1)]),
+    ("yaml", &[("\n- ", 2), ("---\n", 2), (": |", 4)]),
+    (
+        "toml",
+        &[
+            ("\n[", 2),
+            (" = \"", 2),
+            ("[package]", 5),
+            ("[dependencies]", 5),
+        ],
+    ),
+    (
+        "html",
+        &[
+            ("<div", 4),
+            ("</", 3),
+            ("<html", 5),
+            ("<!DOCTYPE", 5),
+            ("class=\"", 3),
+            ("href=", 3),
+        ],
+    ),
+    (
+        "css",
+        &[
+            ("px;", 4),
+            ("color:", 3),
+            ("margin", 3),
+            ("padding", 3),
+            ("display:", 3),
+        ],
+    ),
+    (
+        "sql",
+        &[
+            ("SELECT ", 4),
+            ("FROM ", 3),
+            ("WHERE ", 3),
+            ("INSERT INTO", 5),
+            ("CREATE TABLE", 5),
+            ("JOIN ", 3),
+        ],
+    ),
+    (
+        "diff",
+        &[
+            ("@@ ", 5),
+            ("diff --git", 5),
+            ("+++ ", 4),
+            ("--- ", 2),
+            ("\n+", 1),
+            ("\n-", 1),
+        ],
+    ),
+];
+
+/// Guess the syntax of a raw block without a language tag from its content.
+///
+/// Shebangs, modelines and the first-line patterns of the syntaxes are trusted.
+/// Otherwise, the content is scored against a few common languages,
+/// and the best match is returned if its confidence reaches the threshold.
+pub(crate) fn guess_syntax<'a>(
+    content: &str,
+    syntax_set: &'a SyntaxSet,
+    find: impl Fn(&str) -> Option<&'a SyntaxReference>,
+    threshold: f32,
+) -> Option<&'a SyntaxReference> {
+    let content = content.trim_start();
+    shebang(content)
+        .or_else(|| modeline(content))
+        .and_then(&find)
+        .or_else(|| syntax_set.find_syntax_by_first_line(content))
+        .or_else(|| {
+            let (lang, confidence) = score(content)?;
+            (confidence >= threshold).then(|| find(lang)).flatten()
+        })
+}
+
+/// The language of the interpreter in a shebang like `#!/usr/bin/env python3`.
+fn shebang(content: &str) -> Option<&str> {
+    let line = content.lines().next()?.strip_prefix("#!")?;
+    let mut words = line.split_whitespace();
+    let mut program = words.next()?.rsplit('/').next()?;
+    if program == "env" {
+        program = words.find(|word| !word.starts_with('-'))?;
+    }
+    let program = program.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    let lang = INTERPRETERS
+        .iter()
+        .find(|(interpreter, _)| *interpreter == program)
+        .map_or(program, |(_, lang)| lang);
+    Some(lang)
+}
+
+/// The language in a Vim or Emacs modeline near the start or end of the content.
+fn modeline(content: &str) -> Option<&str> {
+    let lines: Vec<_> = content.lines().collect();
+    let head = lines.iter().take(MODELINE_LINES);
+    let tail = lines.iter().skip(MODELINE_LINES).rev().take(MODELINE_LINES);
+    head.chain(tail)
+        .find_map(|line| vim_modeline(line).or_else(|| emacs_modeline(line)))
+}
+
+/// Parses modelines like `vim: set ft=rust:` or `vi: filetype=rust`.
+///
+/// Like in Vim, the marker must be at the start of the line or follow whitespace.
+fn vim_modeline(line: &str) -> Option<&str> {
+    let options = ["vim:", "vi:", "ex:"].iter().find_map(|marker| {
+        line.match_indices(marker)
+            .find(|&(i, _)| i == 0 || line[..i].ends_with(char::is_whitespace))
+            .map(|(i, _)| &line[i + marker.len()..])
+    })?;
+    options
+        .split([' ', ':', '\t'])
+        .find_map(|option| {
+            let (key, value) = option.split_once('=')?;
+            matches!(key, "ft" | "filetype" | "syntax").then_some(value)
+        })
+        .filter(|lang| !lang.is_empty())
+}
+
+/// Parses modelines like `-*- mode: rust -*-` or `-*- rust -*-`.
+fn emacs_modeline(line: &str) -> Option<&str> {
+    let (_, rest) = line.split_once("-*-")?;
+    let (variables, _) = rest.split_once("-*-")?;
+    let lang = if variables.contains(':') {
+        variables.split(';').find_map(|variable| {
+            let (key, value) = variable.split_once(':')?;
+            (key.trim() == "mode").then_some(value)
+        })?
+    } else {
+        variables
+    };
+    Some(lang.trim()).filter(|lang| !lang.is_empty())
+}
+
+/// Score the content against the hints of each language.
+///
+/// Returns the best language along with a confidence between 0 and 1:
+/// its share of the total score, reduced if there is little evidence at all.
+fn score(content: &str) -> Option<(&'static str, f32)> {
+    let scores = HINTS.iter().map(|(lang, hints)| {
+        let score: usize = hints
+            .iter()
+            .map(|(pattern, weight)| content.matches(pattern).count() * weight)
+            .sum();
+        (*lang, score)
+    });
+    let (total, best) = scores.fold((0, None), |(total, best), (lang, score)| {
+        let best = match best {
+            Some((_, best_score)) if best_score >= score => best,
+            _ => Some((lang, score)),
+        };
+        (total + score, best)
+    });
+    let (lang, best) = best.filter(|&(_, score)| score > 0)?;
+    let evidence = best.min(CONFIDENT_SCORE) as f32 / CONFIDENT_SCORE as f32;
+    Some((lang, best as f32 / total as f32 * evidence))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shebang() {
+        assert_eq!(shebang("#!/bin/sh\necho hi"), Some("sh"));
+        assert_eq!(shebang("#! /bin/bash"), Some("bash"));
+        assert_eq!(shebang("#!/usr/bin/env python3"), Some("python"));
+        assert_eq!(shebang("#!/usr/bin/env -S python3 -u"), Some("python"));
+        assert_eq!(shebang("#!/usr/bin/python3.11"), Some("python"));
+        assert_eq!(shebang("#!/usr/bin/env node"), Some("js"));
+        assert_eq!(shebang("#!/usr/local/bin/deno run"), Some("ts"));
+        assert_eq!(shebang("#!/usr/bin/env"), None);
+        assert_eq!(shebang("# comment\n#!/bin/sh"), None);
+    }
+
+    #[test]
+    fn test_vim_modeline() {
+        assert_eq!(vim_modeline("// vim: set ft=rust:"), Some("rust"));
+        assert_eq!(vim_modeline("# vi: filetype=python"), Some("python"));
+        assert_eq!(vim_modeline("vim:syntax=c"), Some("c"));
+        assert_eq!(vim_modeline("/* ex: ts=4 ft=go */"), Some("go"));
+        assert_eq!(vim_modeline("\tvim: sw=2:ft=toml:"), Some("toml"));
+        assert_eq!(vim_modeline("// vim: set ts=4:"), None);
+        assert_eq!(vim_modeline("// vim: ft="), None);
+        // The marker must not be part of a word.
+        assert_eq!(vim_modeline("navi: ft=rust"), None);
+        assert_eq!(vim_modeline("cd ~/navi:ft=rust"), None);
+        assert_eq!(vim_modeline("navi: x vi: ft=rust"), Some("rust"));
+    }
+
+    #[test]
+    fn test_emacs_modeline() {
+        assert_eq!(
+            emacs_modeline("# -*- mode: python; coding: utf-8 -*-"),
+            Some("python")
+        );
+        assert_eq!(emacs_modeline("// -*- rust -*-"), Some("rust"));
+        assert_eq!(emacs_modeline(";; -*- coding: utf-8 -*-"), None);
+        assert_eq!(emacs_modeline("-*- -*-"), None);
+        assert_eq!(emacs_modeline("-*- rust"), None);
+    }
+
+    #[test]
+    fn test_modeline_position() {
+        let body = "x\n".repeat(2 * MODELINE_LINES);
+        assert_eq!(modeline(&format!("// vim: ft=c\n{body}")), Some("c"));
+        assert_eq!(modeline(&format!("{body}// -*- c -*-\n")), Some("c"));
+        // Modelines in the middle are ignored.
+        assert_eq!(modeline(&format!("{body}// vim: ft=c\n{body}")), None);
+    }
+
+    #[test]
+    fn test_score() {
+        assert_eq!(score("x = 1"), None);
+        // Enough evidence for a single language.
+        let hello = "fn main() {\n    println!(\"Hello\");\n}";
+        assert_eq!(score(hello), Some(("rs", 0.6)));
+        assert_eq!(score("def f(self):\n    return None"), Some(("py", 0.6)));
+        // Little evidence.
+        assert_eq!(score("fn x"), Some(("rs", 0.2)));
+        // Evidence for several languages.
+        assert_eq!(score("echo hi; function f"), Some(("js", 0.15)));
+    }
+
+    #[test]
+    fn test_guess_threshold() {
+        let syntax_set = two_face::syntax::extra_newlines();
+        let find = |lang: &str| syntax_set.find_syntax_by_token(lang);
+        let guess = |content, threshold| {
+            guess_syntax(content, &syntax_set, find, threshold).map(|syntax| syntax.name.as_str())
+        };
+
+        let hello = "fn main() {\n    println!(\"Hello\");\n}";
+        assert_eq!(guess(hello, 0.5), Some("Rust"));
+        assert_eq!(guess(hello, 0.6), Some("Rust"));
+        assert_eq!(guess(hello, 0.61), None);
+        assert_eq!(guess("fn x", 0.5), None);
+        // Shebangs and modelines are trusted regardless of the threshold.
+        assert_eq!(guess("#!/usr/bin/env python3\nx = 1", 1.0), Some("Python"));
+        assert_eq!(guess("x = 1\n# vim: ft=python", 1.0), Some("Python"));
+    }
+}
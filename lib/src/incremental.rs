@@ -0,0 +1,222 @@
+use std::ops::Range;
+
+use termcolor::ColorSpec;
+use typst_syntax::{LinkedNode, Source};
+
+use crate::{annotate::write_sgr, lines::split_lines, Error, Highlighter};
+
+/// Highlights a document that is edited over time, e.g. in an editor.
+///
+/// The document is kept as a [`Source`], which is reparsed incrementally on each edit.
+/// Only the lines around the reparsed part are highlighted again.
+///
+/// Every line is highlighted on its own:
+/// it does not contain its line break, and its colors are reset at its end.
+/// Because a [`Source`] is always parsed as markup, the configured [`SyntaxMode`] is ignored.
+/// Neither Discord output nor the size limits are supported.
+///
+/// ```
+/// # use typst_ansi_hl::{Highlighter, IncrementalHighlighter};
+/// let mut doc = IncrementalHighlighter::new(Highlighter::default(), "= Title\nSome text")?;
+/// let change = doc.edit(10..14, "#strong[text]")?;
+/// assert_eq!(change.replaced, 1..2);
+/// assert_eq!(change.lines.len(), 1);
+/// # Ok::<(), typst_ansi_hl::Error>(())
+/// ```
+///
+/// [`SyntaxMode`]: crate::SyntaxMode
+#[derive(Debug, Clone)]
+pub struct IncrementalHighlighter {
+    highlighter: Highlighter,
+    source: Source,
+    lines: Vec<String>,
+}
+
+/// The lines that changed because of an edit.
+///
+/// See [`IncrementalHighlighter::edit`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineChange {
+    /// The lines before the edit that need to be replaced.
+    pub replaced: Range<usize>,
+    /// The newly highlighted lines, starting at `replaced.start`.
+    pub lines: Vec<String>,
+}
+
+impl IncrementalHighlighter {
+    /// Parse and highlight the given text.
+    pub fn new(highlighter: Highlighter, text: impl Into<String>) -> Result<Self, Error> {
+        let source = Source::detached(text);
+        let mut this = IncrementalHighlighter {
+            highlighter,
+            source,
+            lines: Vec::new(),
+        };
+        this.lines = this.highlight_lines(0..this.source.len_lines())?;
+        Ok(this)
+    }
+
+    /// The current source.
+    pub fn source(&self) -> &Source {
+        &self.source
+    }
+
+    /// The highlighted lines of the current source.
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    /// Replace the given byte range of the source with new text.
+    ///
+    /// Returns the lines whose highlighted output changed,
+    /// or an error if the range is out of bounds or not on character boundaries.
+    pub fn edit(&mut self, replace: Range<usize>, with: &str) -> Result<LineChange, Error> {
+        if self.source.text().get(replace.clone()).is_none() {
+            return Err(Error::InvalidRange(replace));
+        }
+        // `Source::edit` can leave the line table stale when a `\r\n` is split or joined,
+        // which is only possible if a `\r` precedes the range or a `\n` follows it.
+        let text = self.source.text();
+        let at_crlf =
+            text[..replace.start].ends_with('\r') || text[replace.end..].starts_with('\n');
+        let old_len_lines = self.source.len_lines();
+        let reparsed = self.source.edit(replace, with);
+
+        let (first, last) = if at_crlf {
+            // Rebuild the source and highlight every line again.
+            self.source = Source::detached(self.source.text());
+            (0, self.source.len_lines() - 1)
+        } else {
+            // A node may be highlighted differently depending on its next sibling,
+            // e.g. an identifier followed by arguments, so the preceding byte is included as well.
+            (
+                self.line_of(reparsed.start.saturating_sub(1)),
+                self.line_of(reparsed.end),
+            )
+        };
+        let new_lines = self.highlight_lines(first..last + 1)?;
+
+        // Lines after the reparsed range are unchanged, they just might have moved.
+        let old_end = last + 1 + old_len_lines - self.source.len_lines();
+        let old_lines = &self.lines[first..old_end];
+        let prefix = Iterator::zip(old_lines.iter(), new_lines.iter())
+            .take_while(|(old, new)| old == new)
+            .count();
+        let suffix = Iterator::zip(
+            old_lines[prefix..].iter().rev(),
+            new_lines[prefix..].iter().rev(),
+        )
+        .take_while(|(old, new)| old == new)
+        .count();
+
+        let replaced = first + prefix..old_end - suffix;
+        let lines = new_lines[prefix..new_lines.len() - suffix].to_vec();
+        self.lines.splice(replaced.clone(), lines.iter().cloned());
+        Ok(LineChange { replaced, lines })
+    }
+
+    fn line_of(&self, byte: usize) -> usize {
+        self.source
+            .byte_to_line(byte)
+            .expect("the byte index should be in bounds")
+    }
+
+    fn highlight_lines(&self, lines: Range<usize>) -> Result<Vec<String>, Error> {
+        let line_range = |line| {
+            self.source
+                .line_to_range(line)
+                .expect("the line index should be in bounds")
+        };
+        let start = line_range(lines.start).start;
+        let end = line_range(lines.end - 1).end;
+
+        let root = LinkedNode::new(self.source.root());
+        let spans = self.highlighter.highlight_spans(&root, start..end)?;
+
+        let lines = lines.map(line_range);
+        split_lines(self.source.text(), &spans, lines)
+            .iter()
+            .map(|line| {
+                let mut highlighted = String::new();
+                let mut current = ColorSpec::new();
+                for (color, text) in line {
+                    if *color != current {
+                        write_sgr(&mut highlighted, color)?;
+                        current = color.clone();
+                    }
+                    highlighted.push_str(text);
+                }
+                if !current.is_none() {
+                    highlighted.push_str("\x1B[0m");
+                }
+                Ok(highlighted)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Apply the edit and check that the changed lines turn the old lines into fresh ones.
+    fn check_edit(text: &str, replace: Range<usize>, with: &str) {
+        let highlighter = Highlighter::default();
+        let mut doc = IncrementalHighlighter::new(highlighter.clone(), text).unwrap();
+        let mut lines = doc.lines().to_vec();
+        let change = doc.edit(replace.clone(), with).unwrap();
+        lines.splice(change.replaced, change.lines);
+
+        let mut edited = text.to_string();
+        edited.replace_range(replace, with);
+        let fresh = IncrementalHighlighter::new(highlighter, edited).unwrap();
+        assert_eq!(lines, fresh.lines());
+        assert_eq!(doc.lines(), fresh.lines());
+    }
+
+    #[test]
+    fn test_edit() {
+        let text = "= Title\nSome *strong* text.\n```rust\nlet x = 1;\n```\n#let y = 2\n";
+        // At the start.
+        check_edit(text, 0..0, "#set text(red)\n");
+        check_edit(text, 0..2, "");
+        // At the end.
+        check_edit(text, text.len()..text.len(), "More $x^2$ text.");
+        check_edit(text, text.len() - 3..text.len(), "\"s\"\n");
+        // Inside of a raw block.
+        check_edit(text, 44..45, "\"one\"");
+        check_edit(text, 36..36, "```\nNot raw anymore.\n");
+        // Across lines.
+        check_edit(text, 10..40, "_emph_");
+
+        // With CRLF line breaks that are split or joined.
+        check_edit("a\r\nb\r\nc", 2..3, "\n");
+        check_edit("a\r\nb", 2..2, "x");
+        check_edit("a\r\nb", 1..2, "");
+        check_edit("a\rb", 2..2, "\n");
+        check_edit("a\rb", 2..2, "\n*c*\r\n");
+        check_edit("= A\r\n*b*\r\nc", 4..4, "\r");
+        let text = "a\r\nb\rc\n\r\n*d*";
+        for end in 0..=text.len() {
+            for start in 0..=end {
+                for with in ["", "x", "\n", "\r", "\r\n", "\n\r"] {
+                    check_edit(text, start..end, with);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_edit_invalid_range() {
+        let mut doc = IncrementalHighlighter::new(Highlighter::default(), "ä = Title").unwrap();
+        #[allow(clippy::reversed_empty_ranges)]
+        let ranges = [1..2, 3..20, 4..3];
+        for range in ranges {
+            assert!(matches!(
+                doc.edit(range.clone(), "x"),
+                Err(Error::InvalidRange(invalid)) if invalid == range
+            ));
+        }
+        assert_eq!(doc.source().text(), "ä = Title");
+    }
+}
@@ -7,22 +7,66 @@
 //!     .with_soft_limit(2000)
 //!     .highlight("This is _Typst_ #underline[code].");
 //! ```
-use std::{io::Write, sync::LazyLock};
+use std::{collections::HashMap, fmt, io::Write, ops::Range, sync::Arc};
 
-use syntect::{
-    easy::HighlightLines, highlighting::FontStyle, parsing::SyntaxSet, util::LinesWithEndings,
-};
+use annotate::{Anchor, Annotated, Annotator, Style};
+use fit::Plan;
 use termcolor::{Color, ColorSpec, WriteColor};
-use two_face::theme::{EmbeddedLazyThemeSet, EmbeddedThemeName};
 use typst_syntax::{
     ast::{self, AstNode},
-    LinkedNode, Tag,
+    LinkedNode, SyntaxNode, Tag,
 };
+use unicode_segmentation::UnicodeSegmentation;
+
+mod annotate;
+mod ansi;
+#[cfg(feature = "anstyle")]
+pub mod anstyle;
+mod budget;
+mod chunks;
+mod elide;
+mod fit;
+#[cfg(feature = "raw-highlighting")]
+mod guess;
+mod incremental;
+mod lines;
+#[cfg(feature = "ratatui")]
+pub mod ratatui;
+#[cfg(feature = "raw-highlighting")]
+mod raw;
+#[cfg(feature = "reedline")]
+mod reedline;
+#[cfg(feature = "rustyline")]
+mod rustyline;
+mod split;
+#[cfg(test)]
+mod testing;
+mod truncate;
+
+pub use budget::MessagePart;
+pub use chunks::HighlightChunks;
+pub use elide::Elision;
+pub use fit::Degradation;
+pub use incremental::{IncrementalHighlighter, LineChange};
+pub use lines::HighlightedLine;
+#[cfg(feature = "raw-highlighting")]
+pub use raw::{dump_syntaxes, load_syntaxes};
 
 /// Module with external dependencies exposed by this library.
 pub mod ext {
+    #[cfg(feature = "anstyle")]
+    pub use ::anstyle;
+    #[cfg(feature = "ratatui")]
+    pub use ::ratatui;
+    #[cfg(feature = "reedline")]
+    pub use ::reedline;
+    #[cfg(feature = "rustyline")]
+    pub use ::rustyline;
+    #[cfg(feature = "raw-highlighting")]
     pub use syntect;
     pub use termcolor;
+    #[cfg(feature = "tokio")]
+    pub use tokio;
     pub use typst_syntax;
 }
 
@@ -35,10 +79,125 @@ pub enum Error {
     #[error(transparent)]
     Io(#[from] std::io::Error),
     #[error(transparent)]
+    Fmt(#[from] fmt::Error),
+    #[cfg(feature = "raw-highlighting")]
+    #[error(transparent)]
     Syntect(#[from] syntect::Error),
+    #[cfg(feature = "raw-highlighting")]
+    #[error(transparent)]
+    SyntaxLoading(#[from] syntect::LoadingError),
+    #[error("range {0:?} is out of bounds or not on a character boundary")]
+    InvalidRange(Range<usize>),
+    /// A raw block could not be highlighted with [`ErrorPolicy::Strict`].
+    #[error("failed to highlight the raw block at {range:?}")]
+    Raw {
+        /// The byte range of the raw block in the input.
+        range: Range<usize>,
+        source: Box<Error>,
+    },
+}
+
+/// What to do when a raw block cannot be highlighted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Write the raw block uncolored and report a [`Warning`].
+    #[default]
+    Fallback,
+    /// Fail with [`Error::Raw`].
+    Strict,
+}
+
+/// The unit that sizes like the soft and the hard limit are measured in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum SizeUnit {
+    /// UTF-8 bytes.
+    #[default]
+    Bytes,
+    /// Unicode scalar values, i.e. [`char`]s. Discord counts these.
+    Chars,
+    /// UTF-16 code units, as counted by JavaScript.
+    Utf16,
+    /// Extended grapheme clusters, i.e. what users perceive as characters.
+    ///
+    /// Every byte of an escape sequence counts as one grapheme cluster,
+    /// even though it might merge with the text surrounding the escape sequence.
+    Graphemes,
+}
+
+impl SizeUnit {
+    /// The size of the text in this unit.
+    ///
+    /// ```
+    /// # use typst_ansi_hl::SizeUnit;
+    /// assert_eq!(SizeUnit::Bytes.measure("∑ñ"), 5);
+    /// assert_eq!(SizeUnit::Chars.measure("∑ñ"), 2);
+    /// assert_eq!(SizeUnit::Graphemes.measure("n\u{303}"), 1);
+    /// ```
+    pub fn measure(self, text: &str) -> usize {
+        match self {
+            SizeUnit::Bytes => text.len(),
+            SizeUnit::Chars => text.chars().count(),
+            SizeUnit::Utf16 => text.encode_utf16().count(),
+            SizeUnit::Graphemes => text.graphemes(true).count(),
+        }
+    }
+}
+
+/// Information about how code was highlighted.
+///
+/// Returned by [`Highlighter::highlight_to_with_report`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct HighlightReport {
+    /// The highlight level the output was written with.
+    ///
+    /// Lower than the [level](Highlighter::with_level) of the highlighter
+    /// if colors were reduced to fit the soft limit.
+    pub level: HighlightLevel,
+    /// Parts of the highlighting that were degraded in addition to the ones below the level.
+    pub degradations: Vec<Degradation>,
+    /// The byte ranges of the input that were elided, in order.
+    ///
+    /// See [`Highlighter::with_elision`].
+    pub elided: Vec<Range<usize>>,
+    /// The size of the output, measured in [`HighlightReport::unit`].
+    pub size: usize,
+    /// The unit that the size and the limits are measured in.
+    pub unit: SizeUnit,
+    /// Whether the output is below the soft and the hard limit.
+    ///
+    /// Always true if no limit is set.
+    pub limit_met: bool,
+    /// The byte position in the input at which the output was cut off to stay below the hard limit.
+    pub truncated_at: Option<usize>,
+    /// The language tags of raw blocks that no syntax is known for, in order of appearance.
+    ///
+    /// Always empty without the `raw-highlighting` feature.
+    pub unknown_langs: Vec<String>,
+    /// Problems that were worked around, in order of their position.
+    pub warnings: Vec<Warning>,
+}
+
+/// A problem that was worked around while highlighting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Warning {
+    /// The byte range of the affected node in the input.
+    pub range: Range<usize>,
+    /// What went wrong.
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (at {:?})", self.message, self.range)
+    }
 }
 
 /// The kind of input syntax.
+///
+/// Raw blocks tagged with `typ`, `typc` or `typm` are highlighted
+/// as Typst markup, code or math respectively, just like the surrounding code.
 #[derive(Debug, Clone, Copy)]
 pub enum SyntaxMode {
     Code,
@@ -46,11 +205,49 @@ pub enum SyntaxMode {
     Math,
 }
 
-#[derive(Debug, Clone, Copy)]
+impl SyntaxMode {
+    /// The syntax mode of raw blocks with the given language tag, if they contain Typst code.
+    fn from_lang(lang: &str) -> Option<SyntaxMode> {
+        match lang {
+            "typ" => Some(SyntaxMode::Markup),
+            "typc" => Some(SyntaxMode::Code),
+            "typm" => Some(SyntaxMode::Math),
+            _ => None,
+        }
+    }
+
+    fn parse(self, input: &str) -> SyntaxNode {
+        match self {
+            SyntaxMode::Code => typst_syntax::parse_code(input),
+            SyntaxMode::Markup => typst_syntax::parse(input),
+            SyntaxMode::Math => typst_syntax::parse_math(input),
+        }
+    }
+}
+
+/// A function that may override the style of a single node.
+///
+/// See [`Highlighter::with_style_hook`].
+type StyleHook = dyn Fn(&LinkedNode, Option<Tag>) -> Option<ColorSpec> + Send + Sync;
+
+#[derive(Clone)]
 pub struct Highlighter {
     discord: bool,
     syntax_mode: SyntaxMode,
     soft_limit: Option<usize>,
+    hard_limit: Option<usize>,
+    size_unit: Option<SizeUnit>,
+    elision: Elision,
+    level: HighlightLevel,
+    tag_levels: HashMap<Tag, Option<HighlightLevel>>,
+    style_hook: Option<Arc<StyleHook>>,
+    error_policy: ErrorPolicy,
+    /// Whether the escape sequences in `ansi` raw blocks are replayed as colors and hidden.
+    ///
+    /// Disabled in line editors, where the highlighted text must equal the typed one.
+    ansi_replay: bool,
+    #[cfg(feature = "raw-highlighting")]
+    raw_config: raw::RawConfig,
 }
 
 impl Default for Highlighter {
@@ -59,16 +256,49 @@ impl Default for Highlighter {
             discord: false,
             syntax_mode: SyntaxMode::Markup,
             soft_limit: None,
+            hard_limit: None,
+            size_unit: None,
+            elision: Elision::Off,
+            level: HighlightLevel::All,
+            tag_levels: HashMap::new(),
+            style_hook: None,
+            error_policy: ErrorPolicy::Fallback,
+            ansi_replay: true,
+            #[cfg(feature = "raw-highlighting")]
+            raw_config: raw::RawConfig::default(),
         }
     }
 }
 
+impl fmt::Debug for Highlighter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("Highlighter");
+        debug
+            .field("discord", &self.discord)
+            .field("syntax_mode", &self.syntax_mode)
+            .field("soft_limit", &self.soft_limit)
+            .field("hard_limit", &self.hard_limit)
+            .field("size_unit", &self.size_unit)
+            .field("elision", &self.elision)
+            .field("level", &self.level)
+            .field("tag_levels", &self.tag_levels)
+            .field("style_hook", &self.style_hook.as_ref().map(|_| ".."))
+            .field("error_policy", &self.error_policy)
+            .field("ansi_replay", &self.ansi_replay);
+        #[cfg(feature = "raw-highlighting")]
+        debug.field("raw_config", &self.raw_config);
+        debug.finish()
+    }
+}
+
 impl Highlighter {
     /// Enable output specifically for Discord.
     ///
     /// If enabled, output will be surrounded by a `ansi` language code block.
     /// Additionally, any code blocks will be escaped.
     /// The output might not look like the input.
+    /// Unless another [size unit](Highlighter::with_size_unit) is set,
+    /// the soft limit is measured in characters.
     ///
     /// Default: `false`.
     pub fn for_discord(&mut self) -> &mut Self {
@@ -84,32 +314,304 @@ impl Highlighter {
         self
     }
 
-    /// Softly enforce a byte size limit.
+    /// Softly enforce a size limit, measured in the [size unit](Highlighter::with_size_unit).
     ///
     /// This means that if the size limit is exceeded, less colors are used
     /// in order to get below that size limit.
     /// If it is not possible to get below that limit, the text is printed anyway.
+    ///
+    /// The input is still highlighted only once:
+    /// the sizes of the less colorful outputs are computed from that result.
     pub fn with_soft_limit(&mut self, soft_limit: usize) -> &mut Self {
         self.soft_limit = Some(soft_limit);
         self
     }
 
+    /// Strictly enforce a size limit, measured in the [size unit](Highlighter::with_size_unit).
+    ///
+    /// Like with a soft limit, less colors are used in order to get below that size limit.
+    /// If even no highlighting exceeds the limit, the output is cut off
+    /// at the end of a line or a top-level node, and a marker like `// 12 more lines`
+    /// or `…` is written in its place.
+    /// The Discord code block is still closed after the marker.
+    ///
+    /// If the marker alone exceeds the limit, `…` or no marker at all is written instead.
+    ///
+    /// ```
+    /// # use typst_ansi_hl::Highlighter;
+    /// let output = Highlighter::default()
+    ///     .with_hard_limit(40)
+    ///     .highlight("= Title\nSome text.\nMore text.\nEven more text.\n")?;
+    /// assert_eq!(output, "= Title\nSome text.\n// 2 more lines\n");
+    /// # Ok::<(), typst_ansi_hl::Error>(())
+    /// ```
+    pub fn with_hard_limit(&mut self, hard_limit: usize) -> &mut Self {
+        self.hard_limit = Some(hard_limit);
+        self
+    }
+
+    /// The unit that the soft and the hard limit are measured in.
+    ///
+    /// Default: [`SizeUnit::Chars`] for [Discord](Highlighter::for_discord),
+    /// since that is what its message limit counts, and [`SizeUnit::Bytes`] otherwise.
+    pub fn with_size_unit(&mut self, unit: SizeUnit) -> &mut Self {
+        self.size_unit = Some(unit);
+        self
+    }
+
+    /// Elide long regions of the code to get below the soft or the hard limit.
+    ///
+    /// Content blocks, strings, raw blocks and function bodies are candidates,
+    /// the ones nested most deeply and the largest ones among them first.
+    /// Depending on the [`Elision`], this happens before or after using less colors.
+    ///
+    /// Default: [`Elision::Off`].
+    ///
+    /// ```
+    /// # use typst_ansi_hl::{Elision, Highlighter};
+    /// let output = Highlighter::default()
+    ///     .with_soft_limit(100)
+    ///     .with_elision(Elision::BeforeColors)
+    ///     .highlight("#let greet(name) = [Hello #name, this is a very long greeting text!]")?;
+    /// assert!(output.contains('…'));
+    /// # Ok::<(), typst_ansi_hl::Error>(())
+    /// ```
+    pub fn with_elision(&mut self, elision: Elision) -> &mut Self {
+        self.elision = elision;
+        self
+    }
+
+    /// The highest level to highlight at.
+    ///
+    /// Without a soft or a hard limit, this is the level the output is highlighted at.
+    /// Otherwise, less colors are only used starting from this level.
+    ///
+    /// Default: [`HighlightLevel::All`].
+    ///
+    /// ```
+    /// # use typst_ansi_hl::{Highlighter, HighlightLevel};
+    /// let output = Highlighter::default()
+    ///     .with_level(HighlightLevel::Off)
+    ///     .highlight("#let x = 1")?;
+    /// assert_eq!(output, "#let x = 1");
+    /// # Ok::<(), typst_ansi_hl::Error>(())
+    /// ```
+    pub fn with_level(&mut self, level: HighlightLevel) -> &mut Self {
+        self.level = level;
+        self
+    }
+
+    /// The lowest level at which nodes with the tag are colored.
+    ///
+    /// This changes the order in which tags lose their color to get below the soft limit:
+    /// tags with a higher level lose it first.
+    /// With [`HighlightLevel::Off`], nodes with the tag keep their color at every level,
+    /// even once the highlighting is off otherwise.
+    /// Use [`Highlighter::without_tag_color`] to never color them instead.
+    ///
+    /// Default: the level that lists the tag, see [`HighlightLevel`].
+    ///
+    /// ```
+    /// # use typst_ansi_hl::{ext::typst_syntax::Tag, Highlighter, HighlightLevel};
+    /// // Keep the colors of math as long as possible and drop the ones of strings first.
+    /// let mut highlighter = Highlighter::default();
+    /// highlighter
+    ///     .with_tag_level(Tag::MathOperator, HighlightLevel::L0)
+    ///     .with_tag_level(Tag::MathDelimiter, HighlightLevel::L0)
+    ///     .with_tag_level(Tag::String, HighlightLevel::All);
+    /// let input = "#let s = \"text\"\n$(a + b)^2$";
+    /// let full = highlighter.highlight(input)?;
+    /// assert!(full.contains("\x1b[32m\"text\""));
+    ///
+    /// // Just below the full size, only the string loses its color.
+    /// let output = highlighter.clone().with_soft_limit(full.len()).highlight(input)?;
+    /// assert!(output.contains(" \"text\"\n"));
+    /// assert!(output.contains("\x1b[35m#let"));
+    /// assert!(output.contains("\x1b[36m=") && output.contains("\x1b[36m$"));
+    ///
+    /// // With a tight limit, the math still keeps its colors.
+    /// let output = highlighter.with_soft_limit(70).highlight(input)?;
+    /// assert!(!output.contains("\x1b[36m="));
+    /// assert!(output.contains("\x1b[36m$"));
+    /// # Ok::<(), typst_ansi_hl::Error>(())
+    /// ```
+    pub fn with_tag_level(&mut self, tag: Tag, level: HighlightLevel) -> &mut Self {
+        self.tag_levels.insert(tag, Some(level));
+        self
+    }
+
+    /// Never color nodes with the tag, at any level.
+    ///
+    /// ```
+    /// # use typst_ansi_hl::{ext::typst_syntax::Tag, Highlighter};
+    /// let output = Highlighter::default()
+    ///     .without_tag_color(Tag::Keyword)
+    ///     .highlight("#let x = 1")?;
+    /// assert!(output.contains("#let"));
+    /// assert!(!output.contains("\x1b[35m#let"));
+    /// # Ok::<(), typst_ansi_hl::Error>(())
+    /// ```
+    pub fn without_tag_color(&mut self, tag: Tag) -> &mut Self {
+        self.tag_levels.insert(tag, None);
+        self
+    }
+
+    /// Override the style of individual nodes.
+    ///
+    /// The hook is called for every node with the tag it would be highlighted with.
+    /// If it returns a style, that style is used for the node and its children
+    /// instead of the one derived from the tag.
+    /// Children with a tag of their own are still highlighted as usual.
+    ///
+    /// The hook may be called several times for the same node,
+    /// e.g. when the output is truncated, elided or split to fit the size limits,
+    /// so it should return the same style for the same node every time.
+    /// Its styles are not used once the soft limit turned highlighting off entirely.
+    ///
+    /// ```
+    /// # use typst_ansi_hl::{ext::termcolor::{Color, ColorSpec}, ext::typst_syntax::Tag, Highlighter};
+    /// let output = Highlighter::default()
+    ///     .with_style_hook(|node, tag| {
+    ///         let is_deprecated = tag == Some(Tag::Function) && node.text() == "old";
+    ///         is_deprecated.then(|| ColorSpec::new().set_fg(Some(Color::Red)).clone())
+    ///     })
+    ///     .highlight("#old() #new()")?;
+    /// assert!(output.contains("\x1b[31mold"));
+    /// // Other functions keep the color of their tag.
+    /// assert!(output.contains("\x1b[3m\x1b[34m#new"));
+    /// # Ok::<(), typst_ansi_hl::Error>(())
+    /// ```
+    pub fn with_style_hook(
+        &mut self,
+        hook: impl Fn(&LinkedNode, Option<Tag>) -> Option<ColorSpec> + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.style_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// What to do when a raw block cannot be highlighted.
+    ///
+    /// Default: [`ErrorPolicy::Fallback`].
+    pub fn with_error_policy(&mut self, policy: ErrorPolicy) -> &mut Self {
+        self.error_policy = policy;
+        self
+    }
+
     /// Highlight Typst code and return the highlighted string.
     pub fn highlight(&self, input: &str) -> Result<String, Error> {
-        let mut out = termcolor::Ansi::new(Vec::new());
-        self.highlight_to(input, &mut out)?;
-        Ok(String::from_utf8(out.into_inner()).expect("the output should be entirely UTF-8"))
+        let mut out = String::new();
+        self.highlight_fmt(input, &mut out)?;
+        Ok(out)
+    }
+
+    /// Highlight Typst code and write it to the given formatter or string.
+    ///
+    /// Also see [`Highlighter::highlighted`] for use in [`Display`](fmt::Display) implementations.
+    pub fn highlight_fmt<W: fmt::Write>(&self, input: &str, out: W) -> Result<(), Error> {
+        let parsed = self.parse(input);
+        self.highlight_node_range_fmt(&typst_syntax::LinkedNode::new(&parsed), None, out)
+    }
+
+    /// Wrap Typst code so that it is highlighted when displayed.
+    ///
+    /// Errors while highlighting are reported as [`fmt::Error`].
+    ///
+    /// ```
+    /// # use typst_ansi_hl::Highlighter;
+    /// let highlighter = Highlighter::default();
+    /// println!("{}", highlighter.highlighted("This is _Typst_ #underline[code]."));
+    /// ```
+    pub fn highlighted<'a>(&'a self, input: &'a str) -> Highlighted<'a> {
+        Highlighted {
+            highlighter: self,
+            input,
+        }
     }
 
     /// Highlight Typst code and write it to the given output.
     pub fn highlight_to<W: WriteColor>(&self, input: &str, out: W) -> Result<(), Error> {
-        let parsed = match self.syntax_mode {
-            SyntaxMode::Code => typst_syntax::parse_code(input),
-            SyntaxMode::Markup => typst_syntax::parse(input),
-            SyntaxMode::Math => typst_syntax::parse_math(input),
-        };
+        self.highlight_to_with_report(input, out)?;
+        Ok(())
+    }
+
+    /// Highlight Typst code, write it to the given output and report how it was highlighted.
+    ///
+    /// ```
+    /// # use typst_ansi_hl::Highlighter;
+    /// let mut output = Vec::new();
+    /// let report = Highlighter::default().highlight_to_with_report(
+    ///     "```rust let x = 1;```",
+    ///     typst_ansi_hl::ext::termcolor::Ansi::new(&mut output),
+    /// )?;
+    /// assert!(report.warnings.is_empty());
+    /// assert_eq!(report.level, typst_ansi_hl::HighlightLevel::All);
+    /// # Ok::<(), typst_ansi_hl::Error>(())
+    /// ```
+    pub fn highlight_to_with_report<W: WriteColor>(
+        &self,
+        input: &str,
+        out: W,
+    ) -> Result<HighlightReport, Error> {
+        let parsed = self.parse(input);
         let linked = typst_syntax::LinkedNode::new(&parsed);
-        self.highlight_node_to(&linked, out)
+        self.highlight_node_range_to(&linked, None, out)
+    }
+
+    /// Highlight only a byte range of the Typst code and return the highlighted string.
+    ///
+    /// See [`Highlighter::highlight_range_to`].
+    pub fn highlight_range(&self, input: &str, range: Range<usize>) -> Result<String, Error> {
+        if input.get(range.clone()).is_none() {
+            return Err(Error::InvalidRange(range));
+        }
+        let parsed = self.parse(input);
+        let linked = typst_syntax::LinkedNode::new(&parsed);
+        let mut out = String::new();
+        self.highlight_node_range_fmt(&linked, Some(range), &mut out)?;
+        Ok(out)
+    }
+
+    /// Highlight only a byte range of the Typst code and write it to the given output.
+    ///
+    /// The whole input is parsed, so that the range is highlighted just like it would be
+    /// as part of the whole document, even if it starts in the middle of a raw block or an equation.
+    /// Styles active at the start of the range are applied to its first character,
+    /// and all styles are reset at its end.
+    ///
+    /// ```
+    /// # use typst_ansi_hl::Highlighter;
+    /// let input = "Some *strong\ntext* here";
+    /// let output = Highlighter::default().highlight_range(input, 13..18)?;
+    /// // The strong style is opened again at the start of the range.
+    /// assert_eq!(output, "\x1b[0m\x1b[1m\x1b[33mtext*\x1b[0m");
+    /// # Ok::<(), typst_ansi_hl::Error>(())
+    /// ```
+    pub fn highlight_range_to<W: WriteColor>(
+        &self,
+        input: &str,
+        range: Range<usize>,
+        out: W,
+    ) -> Result<(), Error> {
+        self.highlight_range_to_with_report(input, range, out)?;
+        Ok(())
+    }
+
+    /// Highlight only a byte range of the Typst code, write it to the given output
+    /// and report how it was highlighted.
+    ///
+    /// See [`Highlighter::highlight_range_to`] and [`Highlighter::highlight_to_with_report`].
+    pub fn highlight_range_to_with_report<W: WriteColor>(
+        &self,
+        input: &str,
+        range: Range<usize>,
+        out: W,
+    ) -> Result<HighlightReport, Error> {
+        if input.get(range.clone()).is_none() {
+            return Err(Error::InvalidRange(range));
+        }
+        let parsed = self.parse(input);
+        let linked = typst_syntax::LinkedNode::new(&parsed);
+        self.highlight_node_range_to(&linked, Some(range), out)
     }
 
     /// Highlight a linked syntax node and write it to the given output.
@@ -119,101 +621,148 @@ impl Highlighter {
     /// you can use with this function.
     ///
     /// [`SyntaxNode`]: typst_syntax::SyntaxNode
-    pub fn highlight_node_to<W: WriteColor>(
+    pub fn highlight_node_to<W: WriteColor>(&self, node: &LinkedNode, out: W) -> Result<(), Error> {
+        self.highlight_node_range_to(node, None, out)?;
+        Ok(())
+    }
+
+    fn highlight_node_range_to<W: WriteColor>(
         &self,
         node: &LinkedNode,
-        mut out: W,
-    ) -> Result<(), Error> {
-        fn inner_highlight_node<W: WriteColor>(
-            highlighter: &Highlighter,
-            hl_level: HighlightLevel,
-            node: &LinkedNode,
-            out: &mut DeferredWriter<W>,
-            color: &mut ColorSpec,
-        ) -> Result<(), Error> {
-            let prev_color = color.clone();
-
-            if let Some(tag) = typst_syntax::highlight(node) {
-                out.set_color(&highlighter.tag_to_color(hl_level, tag))?;
-            }
+        range: Option<Range<usize>>,
+        out: W,
+    ) -> Result<HighlightReport, Error> {
+        let fitted = self.fit(node, range)?;
+        let styles = self.resolve_styles(&fitted.annotated, &fitted.plan);
+        fitted.annotated.write(&styles, out)?;
 
-            if let Some(raw) = ast::Raw::from_untyped(node) {
-                highlighter.highlight_raw(hl_level, out, raw)?;
-            } else if node.text().is_empty() {
-                for child in node.children() {
-                    inner_highlight_node(highlighter, hl_level, &child, out, color)?;
-                }
-            } else {
-                write!(out, "{}", node.text())?;
-            }
+        let unit = self.size_unit();
+        let size = fitted.annotated.size(&styles, unit);
+        let below = |limit: Option<usize>| limit.map_or(true, |limit| size < limit);
+        Ok(HighlightReport {
+            level: fitted.plan.level,
+            degradations: fitted.plan.degraded,
+            elided: fitted.elided,
+            size,
+            unit,
+            limit_met: below(self.soft_limit) && below(self.hard_limit),
+            truncated_at: fitted.truncation.map(|truncation| truncation.cut),
+            unknown_langs: fitted.annotated.unknown_langs,
+            warnings: fitted.annotated.warnings,
+        })
+    }
+
+    fn highlight_node_range_fmt<W: fmt::Write>(
+        &self,
+        node: &LinkedNode,
+        range: Option<Range<usize>>,
+        out: W,
+    ) -> Result<(), Error> {
+        let fitted = self.fit(node, range)?;
+        let styles = self.resolve_styles(&fitted.annotated, &fitted.plan);
+        fitted.annotated.write_ansi(&styles, out)?;
+        Ok(())
+    }
 
-            out.set_color(&prev_color)?;
-            *color = prev_color;
+    /// Highlight a linked syntax node, including the Discord wrapper.
+    ///
+    /// If a range is given, only the part of the output inside of it is kept.
+    /// The elided ranges are replaced by a placeholder.
+    /// If a marker is given, it is written after the node, e.g. in place of truncated output.
+    fn annotate(
+        &self,
+        node: &LinkedNode,
+        range: Option<Range<usize>>,
+        elided: &[Range<usize>],
+        marker: Option<&str>,
+    ) -> Result<Annotated, Error> {
+        let mut out = Annotator::new(node.offset()).with_elided(elided);
+        if let Some(range) = range {
+            out = out.with_range(range);
+        }
+        if self.discord {
+            out.write_extra("```ansi\n", Anchor::Always);
+        }
 
-            Ok(())
+        self.write_node(node, &mut out)?;
+        if let Some(marker) = marker {
+            out.write_marker(marker);
         }
 
-        fn inner<W: WriteColor>(
-            highlighter: &Highlighter,
-            node: &LinkedNode,
-            out: W,
-            hl_level: HighlightLevel,
-        ) -> Result<(), Error> {
-            let mut out = DeferredWriter::new(out);
-            if highlighter.discord {
-                writeln!(out, "```ansi")?;
+        if self.discord {
+            // Make sure that the closing fences are on their own line.
+            if !out.at_line_start() {
+                out.write_extra("\n", Anchor::Always);
             }
+            out.write_extra("```\n", Anchor::Always);
+        }
+        Ok(out.finish())
+    }
 
-            inner_highlight_node(highlighter, hl_level, node, &mut out, &mut ColorSpec::new())?;
+    /// Write the output with the styles of the plan to a string.
+    fn render(&self, annotated: &Annotated, plan: &Plan) -> Result<String, Error> {
+        let styles = self.resolve_styles(annotated, plan);
+        let mut out = String::new();
+        annotated.write_ansi(&styles, &mut out)?;
+        Ok(out)
+    }
 
-            if highlighter.discord {
-                // Make sure that the closing fences are on their own line.
-                let mut last_leaf = node.clone();
-                while let Some(child) = last_leaf.children().last() {
-                    last_leaf = child;
-                }
-                if !last_leaf.text().ends_with('\n') {
-                    writeln!(out)?;
-                }
-                writeln!(out, "```")?;
-            }
-            Ok(())
+    fn parse(&self, input: &str) -> SyntaxNode {
+        self.syntax_mode.parse(input)
+    }
+
+    /// Highlight only the given byte range of a linked syntax node.
+    ///
+    /// Returns the ranges of the source text along with their colors.
+    /// Neither the Discord wrapper nor the size limits are applied.
+    fn highlight_spans(
+        &self,
+        node: &LinkedNode,
+        range: Range<usize>,
+    ) -> Result<Vec<(ColorSpec, Range<usize>)>, Error> {
+        let highlighter = Highlighter {
+            discord: false,
+            ..self.clone()
+        };
+        let annotated = highlighter.annotate(node, Some(range), &[], None)?;
+        let styles = highlighter.resolve_styles(&annotated, &Plan::new(self.level));
+        Ok(annotated.spans(&styles))
+    }
+
+    fn write_node(&self, node: &LinkedNode, out: &mut Annotator) -> Result<(), Error> {
+        if !out.overlaps(node.range()) || out.elides(node.range()) {
+            out.skip(node.len());
+            return Ok(());
         }
 
-        if let Some(soft_limit) = self.soft_limit {
-            // Because a soft limit is given, we highlight everything to an in-memory buffer
-            // and check whether the output length is less than the limit.
-            // If the limit was reached, we lower the highlight level.
-            // Otherwise, we write it to the real output.
-            // If the highlight level was reached, we _always_ write the output without highlighting.
-            let mut buf_out = termcolor::Ansi::new(Vec::new());
-            let mut level = HighlightLevel::All;
-            loop {
-                inner(self, node, &mut buf_out, level)?;
-                let mut buf = buf_out.into_inner();
-                if buf.len() < soft_limit || level == HighlightLevel::Off {
-                    out.write_all(&buf)?;
-                    break;
-                } else {
-                    buf.clear();
-                    buf_out = termcolor::Ansi::new(buf);
-                    level = level.restrict();
-                }
+        let prev_layer = out.layer();
+
+        let tag = typst_syntax::highlight(node);
+        let hooked = self.style_hook.as_ref().and_then(|hook| hook(node, tag));
+        match (hooked, tag) {
+            (Some(spec), tag) => out.push(Style::Hooked(spec, tag)),
+            (None, Some(tag)) => out.push(Style::Tag(tag)),
+            (None, None) => {}
+        }
+
+        if let Some(raw) = ast::Raw::from_untyped(node) {
+            self.highlight_raw(out, raw)?;
+        } else if node.text().is_empty() {
+            for child in node.children() {
+                self.write_node(&child, out)?;
             }
         } else {
-            inner(self, node, out, HighlightLevel::All)?;
+            write!(out, "{}", node.text())?;
         }
 
+        out.set_layer(prev_layer);
+
         Ok(())
     }
 
-    fn highlight_raw<W: WriteColor>(
-        &self,
-        hl_level: HighlightLevel,
-        out: &mut DeferredWriter<W>,
-        raw: ast::Raw<'_>,
-    ) -> Result<(), Error> {
+    fn highlight_raw(&self, out: &mut Annotator, raw: ast::Raw<'_>) -> Result<(), Error> {
         let text = raw.to_untyped().clone().into_text();
+        let range = out.pos..out.pos + text.len();
 
         // Collect backticks and escape if discord is enabled.
         let backticks: String = text.chars().take_while(|&c| c == '`').collect();
@@ -234,52 +783,142 @@ impl Highlighter {
 
         // Write opening fence.
         if self.discord && !is_pure_fence {
-            out.set_color(&self.tag_to_color(hl_level, Tag::Comment))?;
-            write!(out, "/* when copying, remove and retype these --> */")?;
+            out.push(Style::Tag(Tag::Comment));
+            out.write_extra(
+                "/* when copying, remove and retype these --> */",
+                Anchor::Next,
+            );
         }
-        out.set_color(&self.tag_to_color(hl_level, Tag::Raw))?;
-        write!(out, "{fence}")?;
+        out.push(Style::Tag(Tag::Raw));
+        write_fence(out, &fence)?;
 
         if include_content {
-            if let Some(lang) = raw.lang() {
-                write!(out, "{}", lang.get())?;
-            }
-
             // Trim starting fences.
             let mut inner = text.trim_start_matches('`');
             // Trim closing fences.
             inner = &inner[..inner.len() - (text.len() - inner.len())];
 
-            if let Some(lang) = raw.lang().filter(|_| hl_level >= HighlightLevel::WithRaw) {
-                let lang = lang.get();
+            let lang = raw.lang().map(|lang| lang.get().as_str());
+            if let Some(lang) = lang {
+                write!(out, "{lang}")?;
                 inner = &inner[lang.len()..]; // Trim language tag.
-                highlight_lang(inner, lang, out)?;
-            } else {
+            }
+
+            if let Some(mode) = lang.and_then(SyntaxMode::from_lang) {
+                self.highlight_nested(mode, inner, out)?;
+            } else if lang == Some("ansi") && self.ansi_replay {
+                ansi::highlight_ansi(inner, range.start, out)?;
+            } else if lang == Some("ansi") {
                 write!(out, "{inner}")?;
+            } else {
+                self.highlight_raw_content(lang, raw.block(), inner, range, out)?;
             }
         }
 
         // Write closing fence.
-        out.set_color(&self.tag_to_color(hl_level, Tag::Raw))?;
-        write!(out, "{fence}")?;
+        out.push(Style::Tag(Tag::Raw));
+        write_fence(out, &fence)?;
         if self.discord && !is_pure_fence {
-            out.set_color(&self.tag_to_color(hl_level, Tag::Comment))?;
-            write!(out, "/* <-- when copying, remove and retype these */")?;
+            out.push(Style::Tag(Tag::Comment));
+            out.write_extra(
+                "/* <-- when copying, remove and retype these */",
+                Anchor::Previous,
+            );
         }
 
         Ok(())
     }
 
-    fn tag_to_color(&self, hl_level: HighlightLevel, tag: Tag) -> ColorSpec {
-        let mut color = ColorSpec::default();
-        if hl_level == HighlightLevel::Off {
-            return color;
+    /// Highlight Typst code inside of a raw block just like the surrounding code.
+    fn highlight_nested(
+        &self,
+        mode: SyntaxMode,
+        inner: &str,
+        out: &mut Annotator,
+    ) -> Result<(), Error> {
+        let parsed = mode.parse(inner);
+        out.push(Style::Plain);
+        let outer_base = out.rebase(out.pos);
+        self.write_node(&LinkedNode::new(&parsed), out)?;
+        out.rebase(outer_base);
+        Ok(())
+    }
+
+    /// Highlight the content of a raw element that doesn't contain Typst code.
+    #[cfg_attr(not(feature = "raw-highlighting"), allow(unused_variables))]
+    fn highlight_raw_content(
+        &self,
+        lang: Option<&str>,
+        block: bool,
+        inner: &str,
+        range: Range<usize>,
+        out: &mut Annotator,
+    ) -> Result<(), Error> {
+        #[cfg(feature = "raw-highlighting")]
+        if let Some(lang) = lang.filter(|lang| self.find_tagged_syntax(lang).is_none()) {
+            out.add_unknown_lang(lang);
+        }
+        #[cfg(feature = "raw-highlighting")]
+        if let Some(syntax) = self.find_raw_syntax(lang, block, inner) {
+            match raw::highlight_syntax(inner, syntax, self.syntax_set()) {
+                Ok(segments) => {
+                    out.add_raw_block(range.start);
+                    let outer = out.layer();
+                    for (color, text) in segments {
+                        out.set_layer(outer);
+                        out.push(Style::Raw(range.start, color));
+                        write!(out, "{text}")?;
+                    }
+                    return Ok(());
+                }
+                Err(err) if self.error_policy == ErrorPolicy::Strict => {
+                    return Err(Error::Raw {
+                        range,
+                        source: Box::new(err.into()),
+                    });
+                }
+                Err(err) => out.warn(Warning {
+                    range,
+                    message: format!("failed to highlight raw block: {err}"),
+                }),
+            }
+        }
+        write!(out, "{inner}")?;
+        Ok(())
+    }
+
+    /// The limit that the highlighting is degraded to get below.
+    fn fit_limit(&self) -> Option<usize> {
+        match (self.soft_limit, self.hard_limit) {
+            (Some(soft_limit), Some(hard_limit)) => Some(soft_limit.min(hard_limit)),
+            (soft_limit, hard_limit) => soft_limit.or(hard_limit),
+        }
+    }
+
+    fn size_unit(&self) -> SizeUnit {
+        match self.size_unit {
+            Some(unit) => unit,
+            None if self.discord => SizeUnit::Chars,
+            None => SizeUnit::Bytes,
+        }
+    }
+
+    fn tag_to_color(&self, plan: &Plan, tag: Tag) -> ColorSpec {
+        let Some(level) = self.color_level(tag) else {
+            return ColorSpec::default();
+        };
+        if plan.level < level || plan.degrades(Degradation::Color(tag)) {
+            return ColorSpec::default();
         }
 
-        let l1 = hl_level >= HighlightLevel::L1;
-        let l2 = hl_level >= HighlightLevel::L2;
-        let l3 = hl_level >= HighlightLevel::L3;
-        let with_styles = hl_level >= HighlightLevel::WithStyles;
+        let with_styles =
+            plan.level >= HighlightLevel::WithStyles && !plan.degrades(Degradation::Styles(tag));
+        self.tag_style(tag, with_styles)
+    }
+
+    /// The color of nodes with the tag, regardless of the plan.
+    fn tag_style(&self, tag: Tag, with_styles: bool) -> ColorSpec {
+        let mut color = ColorSpec::default();
         match tag {
             Tag::Comment => {
                 if self.discord {
@@ -288,106 +927,87 @@ impl Highlighter {
                     color.set_dimmed(true)
                 }
             }
-            Tag::Punctuation if l3 => color.set_fg(None),
+            Tag::Punctuation => color.set_fg(None),
             Tag::Escape => color.set_fg(Some(Color::Cyan)),
-            Tag::Strong if l3 => color.set_fg(Some(Color::Yellow)).set_bold(with_styles),
-            Tag::Emph if l3 => color.set_fg(Some(Color::Yellow)).set_italic(with_styles),
-            Tag::Link if l3 => color.set_fg(Some(Color::Blue)).set_underline(with_styles),
-            Tag::Raw if l2 => color.set_fg(Some(Color::White)),
-            Tag::Label if l1 => color.set_fg(Some(Color::Blue)).set_underline(with_styles),
-            Tag::Ref if l1 => color.set_fg(Some(Color::Blue)).set_underline(with_styles),
-            Tag::Heading if l2 => color.set_fg(Some(Color::Cyan)).set_bold(with_styles),
-            Tag::ListMarker if l2 => color.set_fg(Some(Color::Cyan)),
-            Tag::ListTerm if l2 => color.set_fg(Some(Color::Cyan)),
-            Tag::MathDelimiter if l3 => color.set_fg(Some(Color::Cyan)),
-            Tag::MathOperator if l2 => color.set_fg(Some(Color::Cyan)),
+            Tag::Strong => color.set_fg(Some(Color::Yellow)).set_bold(with_styles),
+            Tag::Emph => color.set_fg(Some(Color::Yellow)).set_italic(with_styles),
+            Tag::Link => color.set_fg(Some(Color::Blue)).set_underline(with_styles),
+            Tag::Raw => color.set_fg(Some(Color::White)),
+            Tag::Label => color.set_fg(Some(Color::Blue)).set_underline(with_styles),
+            Tag::Ref => color.set_fg(Some(Color::Blue)).set_underline(with_styles),
+            Tag::Heading => color.set_fg(Some(Color::Cyan)).set_bold(with_styles),
+            Tag::ListMarker => color.set_fg(Some(Color::Cyan)),
+            Tag::ListTerm => color.set_fg(Some(Color::Cyan)),
+            Tag::MathDelimiter => color.set_fg(Some(Color::Cyan)),
+            Tag::MathOperator => color.set_fg(Some(Color::Cyan)),
             Tag::Keyword => color.set_fg(Some(Color::Magenta)),
-            Tag::Operator if l3 => color.set_fg(Some(Color::Cyan)),
-            Tag::Number if l1 => color.set_fg(Some(Color::Yellow)),
-            Tag::String if l1 => color.set_fg(Some(Color::Green)),
-            Tag::Function if l3 => color.set_fg(Some(Color::Blue)).set_italic(with_styles),
-            Tag::Interpolated if l3 => color.set_fg(Some(Color::White)),
+            Tag::Operator => color.set_fg(Some(Color::Cyan)),
+            Tag::Number => color.set_fg(Some(Color::Yellow)),
+            Tag::String => color.set_fg(Some(Color::Green)),
+            Tag::Function => color.set_fg(Some(Color::Blue)).set_italic(with_styles),
+            Tag::Interpolated => color.set_fg(Some(Color::White)),
             Tag::Error => color.set_fg(Some(Color::Red)),
-            _ => &mut color,
         };
         color
     }
 }
 
-static SYNTAX_SET: LazyLock<SyntaxSet> = LazyLock::new(two_face::syntax::extra_newlines);
-static THEME_SET: LazyLock<EmbeddedLazyThemeSet> = LazyLock::new(two_face::theme::extra);
-
-fn highlight_lang<W: WriteColor>(
-    input: &str,
-    lang: &str,
-    out: &mut DeferredWriter<W>,
-) -> Result<(), Error> {
-    let Some(syntax) = SYNTAX_SET.find_syntax_by_token(lang) else {
-        write!(out, "{input}")?;
-        return Ok(());
-    };
-    let ansi_theme = THEME_SET.get(EmbeddedThemeName::Base16);
-
-    let mut highlighter = HighlightLines::new(syntax, ansi_theme);
-    for line in LinesWithEndings::from(input) {
-        let ranges = highlighter.highlight_line(line, &SYNTAX_SET)?;
-        for (styles, text) in ranges {
-            let fg = styles.foreground;
-            let fg = convert_rgb_to_ansi_color(fg.r, fg.g, fg.b, fg.a);
-            let mut color = ColorSpec::new();
-            color.set_fg(fg);
-
-            let font_style = styles.font_style;
-            color.set_bold(font_style.contains(FontStyle::BOLD));
-            color.set_italic(font_style.contains(FontStyle::ITALIC));
-            color.set_underline(font_style.contains(FontStyle::UNDERLINE));
-
-            out.set_color(&color)?;
-            write!(out, "{text}")?;
+/// Write a raw block fence.
+///
+/// Any zero width joiners used for escaping are not part of the source text.
+fn write_fence(out: &mut Annotator, fence: &str) -> std::io::Result<()> {
+    for part in fence.split_inclusive(ZERO_WIDTH_JOINER) {
+        match part.strip_suffix(ZERO_WIDTH_JOINER) {
+            Some(backticks) => {
+                write!(out, "{backticks}")?;
+                out.write_extra(&ZERO_WIDTH_JOINER.to_string(), Anchor::Next);
+            }
+            None => write!(out, "{part}")?,
         }
     }
-
     Ok(())
 }
 
-/// Converts an RGB color from the theme to a [`Color`].
+/// Typst code that is highlighted when displayed.
 ///
-/// Inspired by an equivalent function in `bat`[^1].
-/// [^1]: https://github.com/sharkdp/bat/blob/07c26adc357f70a48f2b412008d5c37d43e084c5/src/terminal.rs#L6
-fn convert_rgb_to_ansi_color(r: u8, g: u8, b: u8, a: u8) -> Option<Color> {
-    match a {
-        0 => Some(match r {
-            // Use predefined colors for wider support.
-            0x00 => Color::Black,
-            0x01 => Color::Red,
-            0x02 => Color::Green,
-            0x03 => Color::Yellow,
-            0x04 => Color::Blue,
-            0x05 => Color::Magenta,
-            0x06 => Color::Cyan,
-            0x07 => Color::White,
-            _ => Color::Ansi256(r),
-        }),
-        1 => None,
-        _ => Some(Color::Ansi256(ansi_colours::ansi256_from_rgb((r, g, b)))),
+/// Created by [`Highlighter::highlighted`].
+#[derive(Debug, Clone, Copy)]
+pub struct Highlighted<'a> {
+    highlighter: &'a Highlighter,
+    input: &'a str,
+}
+
+impl fmt::Display for Highlighted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.highlighter
+            .highlight_fmt(self.input, f)
+            .map_err(|_| fmt::Error)
     }
 }
 
 /// What things to highlight.
 /// Lower values mean less highlighting.
 ///
-/// Used when a soft limit is set.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-enum HighlightLevel {
+/// Used when a soft limit is set or a [level is chosen](Highlighter::with_level).
+/// The tags listed per level are the defaults, see [`Highlighter::with_tag_level`].
+/// See [`HighlightReport::level`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum HighlightLevel {
+    /// Do not highlight anything.
     Off,
+    /// Highlight comments, escapes, keywords and errors.
     L0,
+    /// Also highlight labels, references, numbers and strings.
     L1,
+    /// Also highlight raw text, headings, list markers and math operators.
     L2,
+    /// Also highlight everything else.
     L3,
-    /// Highlight raw blocks.
+    /// Also highlight the contents of raw blocks.
     WithRaw,
-    /// Use styles like bold, italic, underline.
+    /// Also use styles like bold, italic, underline.
     WithStyles,
+    /// Highlight everything.
     All,
 }
 
@@ -406,68 +1026,145 @@ impl HighlightLevel {
     }
 }
 
-/// A writer that only sets the color when content is written.
-/// This is intended to lessen the size impact of unnecessary escape codes.
-struct DeferredWriter<W> {
-    inner: W,
-    current_color: ColorSpec,
-    next_color: Option<ColorSpec>,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::strip_sgr;
 
-impl<W> DeferredWriter<W> {
-    fn new(writer: W) -> DeferredWriter<W> {
-        DeferredWriter {
-            inner: writer,
-            current_color: ColorSpec::new(),
-            next_color: None,
-        }
+    /// The spans of the contents of the single raw block in the input, relative to its start,
+    /// and the spans of the same contents highlighted at the top level.
+    fn nested_spans(
+        highlighter: &Highlighter,
+        input: &str,
+        lang: &str,
+    ) -> [Vec<(ColorSpec, Range<usize>)>; 2] {
+        let open = input.find(lang).unwrap() + lang.len();
+        let close = input.rfind('\n').unwrap() + 1;
+        let inner = &input[open..close];
+
+        let parsed = highlighter.parse(input);
+        let nested = highlighter
+            .highlight_spans(&LinkedNode::new(&parsed), open..close)
+            .unwrap()
+            .into_iter()
+            .map(|(color, range)| (color, range.start - open..range.end - open))
+            .collect();
+
+        let mut top_level = highlighter.clone();
+        top_level.with_syntax_mode(SyntaxMode::from_lang(lang).unwrap());
+        let parsed = top_level.parse(inner);
+        let expected = top_level
+            .highlight_spans(&LinkedNode::new(&parsed), 0..inner.len())
+            .unwrap();
+        [nested, expected]
     }
-}
 
-impl<W: WriteColor> Write for DeferredWriter<W> {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        if let Some(color) = self.next_color.take() {
-            self.inner.set_color(&color)?;
-            self.current_color = color;
+    #[test]
+    fn test_nested_like_top_level() {
+        let inputs = [
+            (
+                "typ",
+                "Text ```typ\n= Title\n*strong* #let x = (1, \"a\") // c\n```",
+            ),
+            (
+                "typc",
+                "#let y = 2\n```typc\nlet f(x) = x + 1\nf(\"a\")\n```",
+            ),
+            ("typm", "$x$ ```typm\nsqrt(x^2) + \"a\" // c\n```"),
+        ];
+        for level in [HighlightLevel::All, HighlightLevel::L2, HighlightLevel::L0] {
+            let mut highlighter = Highlighter::default();
+            highlighter.with_level(level);
+            for (lang, input) in inputs {
+                let [nested, expected] = nested_spans(&highlighter, input, lang);
+                assert!(nested.iter().any(|(color, _)| !color.is_none()), "{input}");
+                assert_eq!(nested, expected, "{level:?} {input}");
+            }
         }
-        self.inner.write(buf)
     }
 
-    fn flush(&mut self) -> std::io::Result<()> {
-        self.inner.flush()
-    }
-}
+    #[test]
+    fn test_nested_degrades() {
+        let input = "```typ\n*strong* #let x = 1\n```";
+        let mut highlighter = Highlighter::default();
+        let all = highlighter.highlight(input).unwrap();
+        assert!(all.contains("\x1b[1m\x1b[33m*strong*"), "{all:?}");
 
-impl<W: WriteColor> WriteColor for DeferredWriter<W> {
-    fn supports_color(&self) -> bool {
-        self.inner.supports_color()
-    }
+        let l0 = highlighter
+            .with_level(HighlightLevel::L0)
+            .highlight(input)
+            .unwrap();
+        assert!(l0.contains("*strong* \x1b[0m\x1b[35m#let"), "{l0:?}");
 
-    fn set_color(&mut self, spec: &ColorSpec) -> std::io::Result<()> {
-        if &self.current_color == spec {
-            self.next_color = None;
-        } else {
-            self.next_color = Some(spec.clone());
-        }
-        Ok(())
+        let off = highlighter
+            .with_level(HighlightLevel::Off)
+            .highlight(input)
+            .unwrap();
+        assert_eq!(off, input);
     }
 
-    fn reset(&mut self) -> std::io::Result<()> {
-        let mut color = ColorSpec::new();
-        color.set_reset(true);
-        self.next_color = Some(color);
-        Ok(())
-    }
+    #[test]
+    fn test_nested_discord() {
+        let input = "Code: ````typ\n= Title\n```rust\nfn main() {}\n```\n#x\n````\n";
+        let output = Highlighter::default()
+            .for_discord()
+            .highlight(input)
+            .unwrap();
+        let text = strip_sgr(&output);
+        // Both the outer and the inner fences are escaped.
+        assert!(
+            text.contains("`\u{200D}`\u{200D}`\u{200D}`typ\n"),
+            "{text:?}"
+        );
+        assert!(text.contains("`\u{200D}`\u{200D}`rust\n"), "{text:?}");
+        assert!(text.contains("\n`\u{200D}`\u{200D}`/* <--"), "{text:?}");
+        assert!(!text[3..text.len() - 4].contains("```"), "{text:?}");
 
-    fn is_synchronous(&self) -> bool {
-        self.inner.is_synchronous()
+        let unescaped = text
+            .strip_prefix("```ansi\n")
+            .and_then(|text| text.strip_suffix("```\n"))
+            .unwrap()
+            .replace("/* when copying, remove and retype these --> */", "")
+            .replace("/* <-- when copying, remove and retype these */", "")
+            .replace('\u{200D}', "");
+        assert_eq!(unescaped, input);
     }
 
-    fn set_hyperlink(&mut self, link: &termcolor::HyperlinkSpec) -> std::io::Result<()> {
-        self.inner.set_hyperlink(link)
+    #[test]
+    fn test_tagged_node_colored_as_whole() {
+        // All of the node is colored, not only its first leaf like `=`.
+        let output = Highlighter::default()
+            .highlight("= Heading\n*a b*")
+            .unwrap();
+        assert_eq!(
+            output,
+            "\x1b[0m\x1b[1m\x1b[36m= Heading\x1b[0m\n\x1b[0m\x1b[1m\x1b[33m*a b*\x1b[0m"
+        );
     }
 
-    fn supports_hyperlinks(&self) -> bool {
-        self.inner.supports_hyperlinks()
+    #[test]
+    #[cfg(not(feature = "raw-highlighting"))]
+    fn test_raw_without_highlighting() {
+        let input =
+            "```rust\nfn main() {}\n```\n```typ\n#let x = 1\n```\n```ansi\n\x1b[31mred\n```\n";
+        let mut output = termcolor::Ansi::new(Vec::new());
+        let report = Highlighter::default()
+            .for_discord()
+            .highlight_to_with_report(input, &mut output)
+            .unwrap();
+        let output = String::from_utf8(output.into_inner()).unwrap();
+
+        // The fences stay escaped and the content is only in the style of raw text.
+        assert!(
+            output.contains(
+                "\x1b[37m`\u{200D}`\u{200D}`rust\nfn main() {}\n`\u{200D}`\u{200D}`\x1b[0m"
+            ),
+            "{output:?}"
+        );
+        // Typst code and escape sequences don't need the feature.
+        assert!(output.contains("\x1b[35m#let"), "{output:?}");
+        assert!(output.contains("\x1b[31mred\n"), "{output:?}");
+        assert!(!strip_sgr(&output).contains("[31m"), "{output:?}");
+        assert!(report.unknown_langs.is_empty());
     }
 }
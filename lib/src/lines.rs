@@ -0,0 +1,126 @@
+use std::ops::Range;
+
+use termcolor::ColorSpec;
+use typst_syntax::LinkedNode;
+
+use crate::{Error, Highlighter};
+
+/// A line of highlighted text, split into segments of the same color.
+pub type HighlightedLine<'a> = Vec<(ColorSpec, &'a str)>;
+
+impl Highlighter {
+    /// Highlight Typst code and return its lines, each split into colored segments.
+    ///
+    /// Lines don't include their line break.
+    /// Segments spanning multiple lines, e.g. inside of raw blocks, are split at the line breaks.
+    /// A line break is anything Typst considers to be one.
+    ///
+    /// Neither the Discord output nor the size limits are applied.
+    ///
+    /// ```
+    /// # use typst_ansi_hl::Highlighter;
+    /// let lines = Highlighter::default().highlight_lines("= Title\n#let x = 1")?;
+    /// assert_eq!(lines.len(), 2);
+    /// assert_eq!(lines[1][0].1, "#let");
+    /// # Ok::<(), typst_ansi_hl::Error>(())
+    /// ```
+    pub fn highlight_lines<'a>(&self, input: &'a str) -> Result<Vec<HighlightedLine<'a>>, Error> {
+        let parsed = self.parse(input);
+        let linked = LinkedNode::new(&parsed);
+        let spans = self.highlight_spans(&linked, 0..input.len())?;
+        Ok(split_lines(input, &spans, line_ranges(input)))
+    }
+}
+
+/// Splits the colored ranges of the text into the given lines.
+///
+/// The line ranges must be ordered and include their line breaks, which are left out.
+pub(crate) fn split_lines<'a>(
+    text: &'a str,
+    spans: &[(ColorSpec, Range<usize>)],
+    lines: impl IntoIterator<Item = Range<usize>>,
+) -> Vec<HighlightedLine<'a>> {
+    let mut spans = spans.iter().peekable();
+    let mut split = Vec::new();
+    for range in lines {
+        let content = text[range.clone()].trim_end_matches(typst_syntax::is_newline);
+        let content_end = range.start + content.len();
+
+        let mut line = HighlightedLine::new();
+        while let Some((color, span)) = spans.peek() {
+            let visible = span.start.max(range.start)..span.end.min(content_end);
+            if !visible.is_empty() {
+                line.push((color.clone(), &text[visible]));
+            }
+            if span.end > range.end {
+                break;
+            }
+            spans.next();
+        }
+        split.push(line);
+    }
+    split
+}
+
+/// The byte ranges of the lines of the text, including their line breaks.
+///
+/// A line break is anything Typst considers to be one, with `\r\n` counting as a single one.
+/// The last line is empty if the text ends with a line break.
+pub(crate) fn line_ranges(text: &str) -> Vec<Range<usize>> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if typst_syntax::is_newline(c) {
+            let mut end = i + c.len_utf8();
+            if c == '\r' && chars.next_if(|&(_, c)| c == '\n').is_some() {
+                end += 1;
+            }
+            lines.push(start..end);
+            start = end;
+        }
+    }
+    lines.push(start..text.len());
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Highlight the lines and check that their segments concatenate to the lines of the input.
+    fn check_lines<'a>(input: &'a str, expected: &[&str]) -> Vec<HighlightedLine<'a>> {
+        let lines = Highlighter::default().highlight_lines(input).unwrap();
+        let texts: Vec<String> = lines
+            .iter()
+            .map(|line| line.iter().map(|(_, text)| *text).collect())
+            .collect();
+        assert_eq!(texts, expected);
+        lines
+    }
+
+    #[test]
+    fn test_lines_in_raw_block() {
+        let input = "```rust\nlet s = \"a\nb\";\n/* one\ntwo */\n```";
+        let lines = check_lines(
+            input,
+            &["```rust", "let s = \"a", "b\";", "/* one", "two */", "```"],
+        );
+        // The string and the comment are split at the line breaks, keeping their colors.
+        for i in [1, 3] {
+            let (end_color, _) = lines[i].last().unwrap();
+            let (start_color, _) = &lines[i + 1][0];
+            assert!(!end_color.is_none());
+            assert_eq!(end_color, start_color);
+        }
+    }
+
+    #[test]
+    fn test_line_breaks() {
+        check_lines("= A\r\n*b*\rc\r\n", &["= A", "*b*", "c", ""]);
+        check_lines("a\u{2028}b\r\r\n", &["a", "b", "", ""]);
+        check_lines("#let x = 1\n", &["#let x = 1", ""]);
+        check_lines("", &[""]);
+        assert_eq!(line_ranges("a\r\nb\rc\n"), [0..3, 3..5, 5..7, 7..7]);
+    }
+}
@@ -0,0 +1,169 @@
+//! Conversion of highlighted Typst code into [`ratatui`] text.
+//!
+//! ```
+//! # use typst_ansi_hl::Highlighter;
+//! let text = Highlighter::default().highlight_text("= Title\n#let x = 1")?;
+//! assert_eq!(text.lines.len(), 2);
+//! # Ok::<(), typst_ansi_hl::Error>(())
+//! ```
+use ratatui::{
+    style::{Color as TuiColor, Modifier, Style},
+    text::{Line, Span, Text},
+};
+use termcolor::{Color, ColorSpec};
+
+use crate::{Error, Highlighter};
+
+impl Highlighter {
+    /// Highlight Typst code and return it as [`ratatui`] text.
+    ///
+    /// Neither the Discord output nor the size limits are applied.
+    /// See [`Highlighter::highlight_lines`].
+    pub fn highlight_text<'a>(&self, input: &'a str) -> Result<Text<'a>, Error> {
+        let lines = self.highlight_lines(input)?;
+        let lines = lines.into_iter().map(|line| {
+            line.into_iter()
+                .map(|(color, text)| Span::styled(text, to_style(&color)))
+                .collect::<Line>()
+        });
+        Ok(lines.collect())
+    }
+}
+
+/// Convert a [`ColorSpec`] into a [`ratatui`] style.
+pub fn to_style(spec: &ColorSpec) -> Style {
+    let mut style = Style::new();
+    if let Some(fg) = spec.fg() {
+        style = style.fg(to_color(*fg, spec.intense()));
+    }
+    if let Some(bg) = spec.bg() {
+        style = style.bg(to_color(*bg, spec.intense()));
+    }
+
+    let modifiers = [
+        (spec.bold(), Modifier::BOLD),
+        (spec.dimmed(), Modifier::DIM),
+        (spec.italic(), Modifier::ITALIC),
+        (spec.underline(), Modifier::UNDERLINED),
+        (spec.strikethrough(), Modifier::CROSSED_OUT),
+    ];
+    for (enabled, modifier) in modifiers {
+        if enabled {
+            style = style.add_modifier(modifier);
+        }
+    }
+    style
+}
+
+/// Convert a [`Color`] into a [`ratatui`] color.
+///
+/// Intense colors are mapped to their light variants.
+pub fn to_color(color: Color, intense: bool) -> TuiColor {
+    match (color, intense) {
+        (Color::Black, false) => TuiColor::Black,
+        (Color::Black, true) => TuiColor::DarkGray,
+        (Color::Red, false) => TuiColor::Red,
+        (Color::Red, true) => TuiColor::LightRed,
+        (Color::Green, false) => TuiColor::Green,
+        (Color::Green, true) => TuiColor::LightGreen,
+        (Color::Yellow, false) => TuiColor::Yellow,
+        (Color::Yellow, true) => TuiColor::LightYellow,
+        (Color::Blue, false) => TuiColor::Blue,
+        (Color::Blue, true) => TuiColor::LightBlue,
+        (Color::Magenta, false) => TuiColor::Magenta,
+        (Color::Magenta, true) => TuiColor::LightMagenta,
+        (Color::Cyan, false) => TuiColor::Cyan,
+        (Color::Cyan, true) => TuiColor::LightCyan,
+        // In ratatui, `White` is the intense variant.
+        (Color::White, false) => TuiColor::Gray,
+        (Color::White, true) => TuiColor::White,
+        (Color::Ansi256(index), _) => TuiColor::Indexed(index),
+        (Color::Rgb(r, g, b), _) => TuiColor::Rgb(r, g, b),
+        _ => TuiColor::Reset,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_color() {
+        let colors = [
+            (Color::Black, TuiColor::Black, TuiColor::DarkGray),
+            (Color::Red, TuiColor::Red, TuiColor::LightRed),
+            (Color::Green, TuiColor::Green, TuiColor::LightGreen),
+            (Color::Yellow, TuiColor::Yellow, TuiColor::LightYellow),
+            (Color::Blue, TuiColor::Blue, TuiColor::LightBlue),
+            (Color::Magenta, TuiColor::Magenta, TuiColor::LightMagenta),
+            (Color::Cyan, TuiColor::Cyan, TuiColor::LightCyan),
+            (Color::White, TuiColor::Gray, TuiColor::White),
+            (
+                Color::Ansi256(9),
+                TuiColor::Indexed(9),
+                TuiColor::Indexed(9),
+            ),
+            (
+                Color::Rgb(1, 2, 3),
+                TuiColor::Rgb(1, 2, 3),
+                TuiColor::Rgb(1, 2, 3),
+            ),
+        ];
+        for (color, normal, intense) in colors {
+            assert_eq!(to_color(color, false), normal);
+            assert_eq!(to_color(color, true), intense);
+        }
+    }
+
+    #[test]
+    fn test_to_style() {
+        assert_eq!(to_style(&ColorSpec::new()), Style::new());
+
+        let mut spec = ColorSpec::new();
+        spec.set_fg(Some(Color::Red))
+            .set_bg(Some(Color::Ansi256(42)))
+            .set_bold(true)
+            .set_italic(true)
+            .set_underline(true);
+        assert_eq!(
+            to_style(&spec),
+            Style::new()
+                .fg(TuiColor::Red)
+                .bg(TuiColor::Indexed(42))
+                .add_modifier(Modifier::BOLD | Modifier::ITALIC | Modifier::UNDERLINED)
+        );
+
+        // Intense colors become light, without adding a modifier.
+        spec.set_bg(Some(Color::Blue))
+            .set_intense(true)
+            .set_bold(false)
+            .set_italic(false)
+            .set_underline(false)
+            .set_dimmed(true)
+            .set_strikethrough(true);
+        assert_eq!(
+            to_style(&spec),
+            Style::new()
+                .fg(TuiColor::LightRed)
+                .bg(TuiColor::LightBlue)
+                .add_modifier(Modifier::DIM | Modifier::CROSSED_OUT)
+        );
+    }
+
+    #[test]
+    fn test_highlight_text() {
+        let input = "= Title\n#let x = *strong* $x^2$\n\n```rust\nlet y = 1;\n```";
+        let text = Highlighter::default().highlight_text(input).unwrap();
+        let lines: Vec<String> = text
+            .lines
+            .iter()
+            .map(|line| line.spans.iter().map(|span| &*span.content).collect())
+            .collect();
+        assert_eq!(lines, input.lines().collect::<Vec<_>>());
+        assert!(text
+            .lines
+            .iter()
+            .flat_map(|line| &line.spans)
+            .any(|span| span.style.fg.is_some()));
+    }
+}
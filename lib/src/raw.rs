@@ -0,0 +1,459 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    path::Path,
+    sync::{Arc, LazyLock},
+};
+
+use syntect::{
+    easy::HighlightLines,
+    highlighting::FontStyle,
+    parsing::{SyntaxReference, SyntaxSet},
+    util::LinesWithEndings,
+};
+use termcolor::{Color, ColorSpec};
+use two_face::theme::{EmbeddedLazyThemeSet, EmbeddedThemeName};
+
+use crate::{guess::guess_syntax, Error, Highlighter};
+
+static SYNTAX_SET: LazyLock<SyntaxSet> = LazyLock::new(two_face::syntax::extra_newlines);
+static THEME_SET: LazyLock<EmbeddedLazyThemeSet> = LazyLock::new(two_face::theme::extra);
+
+/// Language tags that are not known to the built-in syntaxes, along with their syntax.
+const DEFAULT_ALIASES: &[(&str, &str)] = &[
+    ("console", "bash"),
+    ("sh-session", "bash"),
+    ("shell", "bash"),
+    ("shell-session", "bash"),
+];
+
+/// How raw blocks are highlighted.
+#[derive(Clone)]
+pub(crate) struct RawConfig {
+    syntax_set: Option<Arc<SyntaxSet>>,
+    aliases: HashMap<String, String>,
+    fallback_lang: Option<String>,
+    first_line_detection: bool,
+    guess_threshold: Option<f32>,
+}
+
+impl Default for RawConfig {
+    fn default() -> Self {
+        RawConfig {
+            syntax_set: None,
+            aliases: DEFAULT_ALIASES
+                .iter()
+                .map(|&(from, to)| (from.to_string(), to.to_string()))
+                .collect(),
+            fallback_lang: None,
+            first_line_detection: false,
+            guess_threshold: None,
+        }
+    }
+}
+
+impl fmt::Debug for RawConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RawConfig")
+            .field("syntax_set", &self.syntax_set.as_ref().map(|_| ".."))
+            .field("aliases", &self.aliases)
+            .field("fallback_lang", &self.fallback_lang)
+            .field("first_line_detection", &self.first_line_detection)
+            .field("guess_threshold", &self.guess_threshold)
+            .finish()
+    }
+}
+
+impl Highlighter {
+    /// Use the given syntaxes to highlight raw blocks.
+    ///
+    /// The syntaxes replace the built-in ones,
+    /// so use [`load_syntaxes`] to add syntaxes to the built-in ones.
+    ///
+    /// Default: the syntaxes of [`two_face`].
+    pub fn with_syntax_set(&mut self, syntax_set: impl Into<Arc<SyntaxSet>>) -> &mut Self {
+        self.raw_config.syntax_set = Some(syntax_set.into());
+        self
+    }
+
+    /// Highlight raw blocks tagged with `from` using the syntax `to`.
+    ///
+    /// The syntax is given by its name or by a language tag it is known by.
+    /// Aliases are not resolved recursively.
+    ///
+    /// Default: `console`, `sh-session`, `shell` and `shell-session` are highlighted as `bash`.
+    ///
+    /// ```
+    /// # use typst_ansi_hl::Highlighter;
+    /// let output = Highlighter::default()
+    ///     .with_lang_alias("rs2024", "rust")
+    ///     .highlight("```rs2024 let x = 1;```")?;
+    /// assert!(output.contains("\x1b[35mlet"));
+    /// # Ok::<(), typst_ansi_hl::Error>(())
+    /// ```
+    pub fn with_lang_alias(&mut self, from: impl Into<String>, to: impl Into<String>) -> &mut Self {
+        self.raw_config.aliases.insert(from.into(), to.into());
+        self
+    }
+
+    /// Highlight raw blocks with an unknown language tag as the given language instead.
+    ///
+    /// Raw blocks without a language tag are not affected.
+    ///
+    /// Default: none, such raw blocks are not highlighted.
+    pub fn with_fallback_lang(&mut self, lang: impl Into<String>) -> &mut Self {
+        self.raw_config.fallback_lang = Some(lang.into());
+        self
+    }
+
+    /// Detect the language of raw blocks without a language tag from their first line,
+    /// e.g. a shebang like `#!/bin/sh`.
+    ///
+    /// Only applies to blocks, not to inline raw text.
+    ///
+    /// Default: `false`.
+    pub fn with_first_line_detection(&mut self) -> &mut Self {
+        self.raw_config.first_line_detection = true;
+        self
+    }
+
+    /// Guess the language of raw blocks without a language tag from their content.
+    ///
+    /// Shebangs, Vim and Emacs modelines, and the first line are considered first.
+    /// Otherwise, the content is scored against a few common languages
+    /// and the best guess is only used if its confidence, between 0 and 1,
+    /// reaches the given threshold, so that uncertain guesses stay plain.
+    ///
+    /// Only applies to blocks, not to inline raw text.
+    ///
+    /// Default: off.
+    ///
+    /// ```
+    /// # use typst_ansi_hl::Highlighter;
+    /// let input = "```\nfn main() {\n    println!(\"Hello\");\n}\n```";
+    /// // Guessed as Rust with a confidence of 0.6.
+    /// let output = Highlighter::default().with_lang_guessing(0.5).highlight(input)?;
+    /// assert!(output.contains("\x1b[35mfn"));
+    /// let output = Highlighter::default().with_lang_guessing(0.9).highlight(input)?;
+    /// assert!(output.contains("\nfn main() {\n"));
+    /// # Ok::<(), typst_ansi_hl::Error>(())
+    /// ```
+    pub fn with_lang_guessing(&mut self, threshold: f32) -> &mut Self {
+        self.raw_config.guess_threshold = Some(threshold);
+        self
+    }
+
+    pub(crate) fn syntax_set(&self) -> &SyntaxSet {
+        self.raw_config.syntax_set.as_deref().unwrap_or(&SYNTAX_SET)
+    }
+
+    /// Find the syntax that a language tag refers to, without falling back to another one.
+    pub(crate) fn find_tagged_syntax(&self, lang: &str) -> Option<&SyntaxReference> {
+        let syntax_set = self.syntax_set();
+        match self.raw_config.aliases.get(lang) {
+            Some(alias) => find_syntax(syntax_set, alias),
+            None => syntax_set.find_syntax_by_token(lang),
+        }
+    }
+
+    /// Find the syntax to highlight the content of a raw element with.
+    pub(crate) fn find_raw_syntax(
+        &self,
+        lang: Option<&str>,
+        block: bool,
+        content: &str,
+    ) -> Option<&SyntaxReference> {
+        let syntax_set = self.syntax_set();
+        let config = &self.raw_config;
+        match lang {
+            Some(lang) => self.find_tagged_syntax(lang).or_else(|| {
+                let fallback = config.fallback_lang.as_deref()?;
+                find_syntax(syntax_set, fallback)
+            }),
+            None if !block => None,
+            None => match config.guess_threshold {
+                Some(threshold) => guess_syntax(
+                    content,
+                    syntax_set,
+                    |lang| self.find_tagged_syntax(lang),
+                    threshold,
+                ),
+                None if config.first_line_detection => {
+                    syntax_set.find_syntax_by_first_line(content.trim_start())
+                }
+                None => None,
+            },
+        }
+    }
+}
+
+/// Find a syntax by its name or a language tag it is known by.
+fn find_syntax<'a>(syntax_set: &'a SyntaxSet, lang: &str) -> Option<&'a SyntaxReference> {
+    syntax_set
+        .find_syntax_by_name(lang)
+        .or_else(|| syntax_set.find_syntax_by_token(lang))
+}
+
+/// Load syntax definitions to highlight raw blocks with.
+///
+/// If the path is a directory, all `.sublime-syntax` files in it are added to the built-in syntaxes.
+/// Otherwise, the path must be a dump created by [`dump_syntaxes`], which loads much faster.
+pub fn load_syntaxes(path: impl AsRef<Path>) -> Result<SyntaxSet, Error> {
+    let path = path.as_ref();
+    if path.is_dir() {
+        let mut builder = SYNTAX_SET.clone().into_builder();
+        builder.add_from_folder(path, true)?;
+        Ok(builder.build())
+    } else {
+        syntect::dumps::from_uncompressed_dump_file(path).map_err(invalid_dump)
+    }
+}
+
+/// Write syntax definitions to a file, so that they can be loaded quickly with [`load_syntaxes`].
+pub fn dump_syntaxes(syntax_set: &SyntaxSet, path: impl AsRef<Path>) -> Result<(), Error> {
+    syntect::dumps::dump_to_uncompressed_file(syntax_set, path).map_err(invalid_dump)
+}
+
+fn invalid_dump(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, err).into()
+}
+
+/// Highlight the content of a raw element, splitting it into colored segments.
+///
+/// Nothing is written yet, so that the content can still be written uncolored if this fails.
+pub(crate) fn highlight_syntax<'a>(
+    input: &'a str,
+    syntax: &SyntaxReference,
+    syntax_set: &SyntaxSet,
+) -> Result<Vec<(ColorSpec, &'a str)>, syntect::Error> {
+    let mut segments = Vec::new();
+    let ansi_theme = THEME_SET.get(EmbeddedThemeName::Base16);
+
+    let mut highlighter = HighlightLines::new(syntax, ansi_theme);
+    for line in LinesWithEndings::from(input) {
+        let ranges = highlighter.highlight_line(line, syntax_set)?;
+        for (styles, text) in ranges {
+            let fg = styles.foreground;
+            let fg = convert_rgb_to_ansi_color(fg.r, fg.g, fg.b, fg.a);
+            let mut color = ColorSpec::new();
+            color.set_fg(fg);
+
+            let font_style = styles.font_style;
+            color.set_bold(font_style.contains(FontStyle::BOLD));
+            color.set_italic(font_style.contains(FontStyle::ITALIC));
+            color.set_underline(font_style.contains(FontStyle::UNDERLINE));
+
+            segments.push((color, text));
+        }
+    }
+
+    Ok(segments)
+}
+
+/// Converts an RGB color from the theme to a [`Color`].
+///
+/// Inspired by an equivalent function in `bat`[^1].
+/// [^1]: https://github.com/sharkdp/bat/blob/07c26adc357f70a48f2b412008d5c37d43e084c5/src/terminal.rs#L6
+fn convert_rgb_to_ansi_color(r: u8, g: u8, b: u8, a: u8) -> Option<Color> {
+    match a {
+        0 => Some(match r {
+            // Use predefined colors for wider support.
+            0x00 => Color::Black,
+            0x01 => Color::Red,
+            0x02 => Color::Green,
+            0x03 => Color::Yellow,
+            0x04 => Color::Blue,
+            0x05 => Color::Magenta,
+            0x06 => Color::Cyan,
+            0x07 => Color::White,
+            _ => Color::Ansi256(r),
+        }),
+        1 => None,
+        _ => Some(Color::Ansi256(ansi_colours::ansi256_from_rgb((r, g, b)))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use syntect::parsing::{SyntaxDefinition, SyntaxSetBuilder};
+
+    use super::*;
+    use crate::{ErrorPolicy, HighlightLevel};
+
+    /// A highlighter with a syntax that fails to highlight, as it refers to an unknown syntax.
+    fn broken_highlighter() -> Highlighter {
+        let definition = SyntaxDefinition::load_from_str(
+            "name: Broken\n\
+             scope: source.broken\n\
+             file_extensions: [broken]\n\
+             contexts:\n  \
+               main:\n    \
+                 - match: 'x'\n      \
+                   push: scope:source.unknown\n",
+            true,
+            None,
+        )
+        .unwrap();
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add(definition);
+        let mut highlighter = Highlighter::default();
+        highlighter.with_syntax_set(builder.build());
+        highlighter
+    }
+
+    #[test]
+    fn test_error_policy() {
+        let input = "Some text ```broken x y```";
+        let block = 10..input.len();
+
+        let mut strict = broken_highlighter();
+        strict.with_error_policy(ErrorPolicy::Strict);
+        match strict.highlight(input) {
+            Err(Error::Raw { range, .. }) => assert_eq!(range, block),
+            other => panic!("expected a raw block error, got {other:?}"),
+        }
+
+        let fallback = broken_highlighter();
+        let mut output = Vec::new();
+        let report = fallback
+            .highlight_to_with_report(input, termcolor::Ansi::new(&mut output))
+            .unwrap();
+        assert_eq!(report.warnings.len(), 1);
+        assert_eq!(report.warnings[0].range, block);
+        assert_eq!(report.level, HighlightLevel::All);
+        // The content is written uncolored, inside of the color of the raw block.
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("```broken x y```"), "{output:?}");
+    }
+
+    /// The name of the syntax that the content of a raw element is highlighted with.
+    fn syntax_name(highlighter: &Highlighter, lang: Option<&str>, block: bool) -> Option<String> {
+        let content = "#!/bin/bash\necho hi\n";
+        let syntax = highlighter.find_raw_syntax(lang, block, content)?;
+        Some(syntax.name.clone())
+    }
+
+    #[test]
+    fn test_lang_alias() {
+        let mut highlighter = Highlighter::default();
+        assert_eq!(syntax_name(&highlighter, Some("rs2024"), true), None);
+        highlighter.with_lang_alias("rs2024", "rust");
+        let rust = Some("Rust".to_string());
+        assert_eq!(syntax_name(&highlighter, Some("rs2024"), true), rust);
+        // The alias takes precedence over the language tags of the syntaxes.
+        highlighter.with_lang_alias("rs", "Bourne Again Shell (bash)");
+        let bash = Some("Bourne Again Shell (bash)".to_string());
+        assert_eq!(syntax_name(&highlighter, Some("rs"), true), bash);
+
+        let output = highlighter.highlight("```rs2024 let x = 1;```").unwrap();
+        assert!(output.contains("\x1b[35mlet"), "{output:?}");
+    }
+
+    #[test]
+    fn test_default_aliases() {
+        let highlighter = Highlighter::default();
+        for (from, _) in DEFAULT_ALIASES {
+            assert!(SYNTAX_SET.find_syntax_by_token(from).is_none(), "{from}");
+            let bash = Some("Bourne Again Shell (bash)".to_string());
+            assert_eq!(syntax_name(&highlighter, Some(from), true), bash, "{from}");
+        }
+        let output = highlighter.highlight("```console\necho hi\n```").unwrap();
+        assert!(output.contains("\x1b[36mecho"), "{output:?}");
+    }
+
+    #[test]
+    fn test_fallback_lang() {
+        let mut highlighter = Highlighter::default();
+        highlighter.with_fallback_lang("rust");
+        let rust = Some("Rust".to_string());
+        assert_eq!(syntax_name(&highlighter, Some("unknown"), true), rust);
+        assert_eq!(syntax_name(&highlighter, Some("unknown"), false), rust);
+        // Known languages and untagged raw elements are not affected.
+        let python = Some("Python".to_string());
+        assert_eq!(syntax_name(&highlighter, Some("py"), true), python);
+        assert_eq!(syntax_name(&highlighter, None, true), None);
+        assert_eq!(syntax_name(&highlighter, None, false), None);
+
+        // The language is still reported as unknown.
+        let mut output = Vec::new();
+        let report = highlighter
+            .highlight_to_with_report(
+                "```unknown\nlet x = 1;\n```",
+                termcolor::Ansi::new(&mut output),
+            )
+            .unwrap();
+        assert_eq!(report.unknown_langs, ["unknown"]);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("\x1b[35mlet"), "{output:?}");
+    }
+
+    #[test]
+    fn test_first_line_detection() {
+        let mut highlighter = Highlighter::default();
+        assert_eq!(syntax_name(&highlighter, None, true), None);
+        highlighter.with_first_line_detection();
+        let bash = Some("Bourne Again Shell (bash)".to_string());
+        assert_eq!(syntax_name(&highlighter, None, true), bash);
+        assert_eq!(syntax_name(&highlighter, None, false), None);
+
+        let block = highlighter
+            .highlight("```\n#!/bin/bash\necho hi\n```")
+            .unwrap();
+        assert!(block.contains("\x1b[36mecho"), "{block:?}");
+        let inline = highlighter.highlight("`#!/bin/bash echo hi`").unwrap();
+        assert!(inline.contains("#!/bin/bash echo hi"), "{inline:?}");
+    }
+
+    #[test]
+    fn test_load_and_dump_syntaxes() {
+        let dir =
+            std::env::temp_dir().join(format!("typst-ansi-hl-syntaxes-{}", std::process::id()));
+        let syntaxes = dir.join("syntaxes");
+        std::fs::create_dir_all(&syntaxes).unwrap();
+        std::fs::write(
+            syntaxes.join("greeting.sublime-syntax"),
+            "name: Greeting\n\
+             scope: source.greeting\n\
+             file_extensions: [greeting]\n\
+             contexts:\n  \
+               main:\n    \
+                 - match: 'hello'\n      \
+                   scope: keyword.control\n",
+        )
+        .unwrap();
+
+        let names = |syntax_set: &SyntaxSet| {
+            let mut names: Vec<_> = syntax_set
+                .syntaxes()
+                .iter()
+                .map(|s| s.name.clone())
+                .collect();
+            names.sort();
+            names
+        };
+        let loaded = load_syntaxes(&syntaxes).unwrap();
+        // The syntaxes are added to the built-in ones.
+        assert!(loaded.find_syntax_by_token("greeting").is_some());
+        assert!(loaded.find_syntax_by_token("rust").is_some());
+        assert_eq!(loaded.syntaxes().len(), SYNTAX_SET.syntaxes().len() + 1);
+
+        let dump = dir.join("syntaxes.packdump");
+        dump_syntaxes(&loaded, &dump).unwrap();
+        let reloaded = load_syntaxes(&dump).unwrap();
+        assert_eq!(names(&reloaded), names(&loaded));
+
+        let mut highlighter = Highlighter::default();
+        highlighter.with_syntax_set(reloaded);
+        let output = highlighter.highlight("```greeting hello```").unwrap();
+        assert!(output.contains("\x1b[35mhello"), "{output:?}");
+
+        let invalid = dir.join("invalid.packdump");
+        std::fs::write(&invalid, "not a dump").unwrap();
+        assert!(matches!(
+            load_syntaxes(&invalid),
+            Err(Error::Io(err)) if err.kind() == std::io::ErrorKind::InvalidData
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,94 @@
+//! Syntax highlighting for [`reedline`].
+use nu_ansi_term::{Color as NuColor, Style};
+use reedline::StyledText;
+use termcolor::{Color, ColorSpec};
+use typst_syntax::LinkedNode;
+
+use crate::Highlighter;
+
+/// Highlights the line as it is typed.
+///
+/// The line is parsed according to the configured [`SyntaxMode`](crate::SyntaxMode).
+/// Neither the Discord output nor the size limits are applied,
+/// and escape sequences in `ansi` raw blocks are kept as typed,
+/// so that the styled text equals the line.
+impl reedline::Highlighter for Highlighter {
+    fn highlight(&self, line: &str, _cursor: usize) -> StyledText {
+        let highlighter = Highlighter {
+            ansi_replay: false,
+            ..self.clone()
+        };
+        let parsed = highlighter.parse(line);
+        let linked = LinkedNode::new(&parsed);
+
+        let mut styled = StyledText::new();
+        match highlighter.highlight_spans(&linked, 0..line.len()) {
+            Ok(spans) => {
+                for (color, range) in spans {
+                    styled.push((to_style(&color), line[range].to_string()));
+                }
+            }
+            Err(_) => styled.push((Style::new(), line.to_string())),
+        }
+        styled
+    }
+}
+
+fn to_style(spec: &ColorSpec) -> Style {
+    Style {
+        foreground: spec.fg().map(|&fg| to_color(fg, spec.intense())),
+        background: spec.bg().map(|&bg| to_color(bg, spec.intense())),
+        is_bold: spec.bold(),
+        is_dimmed: spec.dimmed(),
+        is_italic: spec.italic(),
+        is_underline: spec.underline(),
+        is_strikethrough: spec.strikethrough(),
+        ..Style::new()
+    }
+}
+
+fn to_color(color: Color, intense: bool) -> NuColor {
+    match (color, intense) {
+        (Color::Black, false) => NuColor::Black,
+        (Color::Black, true) => NuColor::DarkGray,
+        (Color::Red, false) => NuColor::Red,
+        (Color::Red, true) => NuColor::LightRed,
+        (Color::Green, false) => NuColor::Green,
+        (Color::Green, true) => NuColor::LightGreen,
+        (Color::Yellow, false) => NuColor::Yellow,
+        (Color::Yellow, true) => NuColor::LightYellow,
+        (Color::Blue, false) => NuColor::Blue,
+        (Color::Blue, true) => NuColor::LightBlue,
+        (Color::Magenta, false) => NuColor::Magenta,
+        (Color::Magenta, true) => NuColor::LightMagenta,
+        (Color::Cyan, false) => NuColor::Cyan,
+        (Color::Cyan, true) => NuColor::LightCyan,
+        (Color::White, false) => NuColor::White,
+        (Color::White, true) => NuColor::LightGray,
+        (Color::Ansi256(index), _) => NuColor::Fixed(index),
+        (Color::Rgb(r, g, b), _) => NuColor::Rgb(r, g, b),
+        _ => NuColor::Default,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_styled_text_equals_line() {
+        let highlighter = Highlighter::default();
+        for line in [
+            "```ansi \x1b[31mred\x1b[0m```",
+            "= Title #let x = \"s\" $x^2$",
+        ] {
+            let styled = reedline::Highlighter::highlight(&highlighter, line, 0);
+            let text: String = styled
+                .buffer
+                .iter()
+                .map(|(_, text)| text.as_str())
+                .collect();
+            assert_eq!(text, line);
+        }
+    }
+}
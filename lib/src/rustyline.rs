@@ -0,0 +1,61 @@
+//! Syntax highlighting for [`rustyline`].
+use std::borrow::Cow;
+
+use rustyline::highlight::CmdKind;
+
+use crate::{Elision, Highlighter};
+
+/// Highlights the line as it is typed.
+///
+/// The line is parsed according to the configured [`SyntaxMode`](crate::SyntaxMode).
+/// Neither the Discord output nor the size limits are applied,
+/// and escape sequences in `ansi` raw blocks are kept as typed,
+/// so that the highlighted line has the same width as the original one.
+impl rustyline::highlight::Highlighter for Highlighter {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        let highlighter = Highlighter {
+            discord: false,
+            soft_limit: None,
+            hard_limit: None,
+            elision: Elision::Off,
+            ansi_replay: false,
+            ..self.clone()
+        };
+        match highlighter.highlight(line) {
+            Ok(highlighted) => Cow::Owned(highlighted),
+            Err(_) => Cow::Borrowed(line),
+        }
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize, kind: CmdKind) -> bool {
+        // The highlighting does not depend on the cursor position.
+        kind != CmdKind::MoveCursor
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::strip_sgr;
+
+    #[test]
+    fn test_ansi_kept() {
+        let line = "```ansi \x1b[1;31mred\x1b[22m```";
+        let highlighter = Highlighter::default();
+        let highlighted = rustyline::highlight::Highlighter::highlight(&highlighter, line, 0);
+        assert!(highlighted.contains("\x1b[1;31mred\x1b[22m"));
+    }
+
+    #[test]
+    fn test_limits_ignored() {
+        let line = "#let x = \"a long string value here\" + 1";
+        let mut highlighter = Highlighter::default();
+        highlighter
+            .with_soft_limit(10)
+            .with_hard_limit(20)
+            .with_elision(Elision::BeforeColors);
+        let highlighted = rustyline::highlight::Highlighter::highlight(&highlighter, line, 0);
+
+        assert_eq!(strip_sgr(&highlighted), line);
+    }
+}
@@ -0,0 +1,238 @@
+use std::ops::Range;
+
+use typst_syntax::{LinkedNode, SyntaxKind};
+
+use crate::{
+    fit::{last_fitting, Plan},
+    lines::line_ranges,
+    Error, HighlightLevel, Highlighter,
+};
+
+impl Highlighter {
+    /// Highlight Typst code and split the output into several messages if it is too large for one.
+    ///
+    /// If the output can't get below the soft or the hard limit even without colors,
+    /// it is split at the ends of lines, preferably between top-level nodes,
+    /// so that each message stays below the limits without colors.
+    /// Each message is then highlighted on its own, using as many colors as fit into it,
+    /// and opens the colors that are active at its start again.
+    /// With [Discord output](Highlighter::for_discord), each message is a complete code block
+    /// and fences of raw blocks stay escaped.
+    ///
+    /// A line that doesn't fit into a message on its own still gets one,
+    /// which is truncated if it exceeds the hard limit.
+    /// Without any limit, the output is a single message.
+    ///
+    /// ```
+    /// # use typst_ansi_hl::Highlighter;
+    /// let messages = Highlighter::default()
+    ///     .for_discord()
+    ///     .with_hard_limit(60)
+    ///     .highlight_messages("= Title\nSome text.\n\n#let x = 1\n\nMore text here.\n")?;
+    /// assert!(messages.len() > 1);
+    /// for message in &messages {
+    ///     assert!(message.starts_with("```ansi\n"));
+    ///     assert!(message.ends_with("```\n"));
+    ///     assert!(message.chars().count() < 60);
+    /// }
+    /// # Ok::<(), typst_ansi_hl::Error>(())
+    /// ```
+    pub fn highlight_messages(&self, input: &str) -> Result<Vec<String>, Error> {
+        let parsed = self.parse(input);
+        let linked = LinkedNode::new(&parsed);
+
+        let fitted = self.fit(&linked, None)?;
+        let exceeds = |limit: &usize| {
+            fitted.truncation.is_some() || self.measure(&fitted.annotated, &fitted.plan) >= *limit
+        };
+        let Some(limit) = self.fit_limit().filter(exceeds) else {
+            return Ok(vec![self.render(&fitted.annotated, &fitted.plan)?]);
+        };
+
+        let mut messages = Vec::new();
+        for range in self.split(&linked, limit)? {
+            let fitted = self.fit(&linked, Some(range))?;
+            messages.push(self.render(&fitted.annotated, &fitted.plan)?);
+        }
+        Ok(messages)
+    }
+
+    /// Split the source of a root node into ranges whose output stays below the limit without colors.
+    ///
+    /// Each range is as long as possible, unless ending it between top-level nodes
+    /// still keeps at least half of it.
+    fn split(&self, node: &LinkedNode, limit: usize) -> Result<Vec<Range<usize>>, Error> {
+        let text = node.get().clone().into_text();
+        let end = text.len();
+        let plan = Plan::new(HighlightLevel::Off);
+        let unit = self.size_unit();
+
+        // The ends of lines, along with whether they are between top-level nodes.
+        let mut children = node
+            .children()
+            .filter(|child| !matches!(child.kind(), SyntaxKind::Space | SyntaxKind::Parbreak))
+            .peekable();
+        let cuts: Vec<(usize, bool)> = line_ranges(&text)
+            .into_iter()
+            .map(|line| line.end)
+            .filter(|&cut| cut < end)
+            .chain([end])
+            .map(|cut| {
+                while children.next_if(|child| child.range().end <= cut).is_some() {}
+                let between = children.peek().map_or(true, |child| child.offset() >= cut);
+                (cut, between)
+            })
+            .collect();
+
+        // Estimate the size of a range from the whole output first,
+        // so that only few ranges need to be highlighted to know their actual size.
+        let annotated = self.annotate(node, None, &[], None)?;
+        let styles = self.resolve_styles(&annotated, &plan);
+        let wrapper = self.annotate(node, Some(0..0), &[], None)?;
+        let wrapper_size = self.measure(&wrapper, &plan);
+        let estimate = |range: Range<usize>| {
+            annotated.size_until(&styles, unit, range.end)
+                - annotated.size_until(&styles, unit, range.start)
+                + wrapper_size
+        };
+
+        let mut ranges = Vec::new();
+        let mut start = 0;
+        while start < end {
+            let cuts = &cuts[cuts.partition_point(|&(cut, _)| cut <= start)..];
+            let last = last_fitting(
+                cuts,
+                |&(cut, _)| estimate(start..cut) < limit,
+                |i| {
+                    let message = self.annotate(node, Some(start..cuts[i].0), &[], None)?;
+                    Ok(self.measure(&message, &plan) < limit)
+                },
+            )?;
+
+            let cut = match last {
+                Some(i) => cuts[..=i]
+                    .iter()
+                    .rev()
+                    .find(|&&(cut, between)| between && cut - start >= (cuts[i].0 - start) / 2)
+                    .map_or(cuts[i].0, |&(cut, _)| cut),
+                // Not even a single line fits.
+                None => cuts[0].0,
+            };
+            ranges.push(start..cut);
+            start = cut;
+        }
+        if ranges.is_empty() {
+            ranges.push(0..0);
+        }
+        Ok(ranges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::strip_sgr;
+
+    const INPUT: &str = "= Title\nSome text here, and then some more text.\n\n\
+        #let f(x) = {\n  let y = x * 2\n  y + 1\n}\n\n\
+        More text with *strong* words in it.\n\
+        ```rust\nfn main() {\n    println!(\"hi\");\n}\n```\nThe end.\n";
+
+    /// Remove the escape sequences and the Discord wrapper from a message.
+    fn strip(message: &str, discord: bool) -> String {
+        let text = strip_sgr(message);
+        if !discord {
+            return text;
+        }
+        let text = text
+            .strip_prefix("```ansi\n")
+            .expect("the code block should be opened");
+        let text = text
+            .strip_suffix("```\n")
+            .expect("the code block should be closed");
+        text.replace("/* when copying, remove and retype these --> */", "")
+            .replace("/* <-- when copying, remove and retype these */", "")
+            .replace('\u{200D}', "")
+    }
+
+    fn highlighters() -> [(Highlighter, bool); 2] {
+        let mut discord = Highlighter::default();
+        discord.for_discord();
+        [(Highlighter::default(), false), (discord, true)]
+    }
+
+    #[test]
+    fn test_messages_below_limit() {
+        for (highlighter, discord) in highlighters() {
+            let unit = highlighter.size_unit();
+            let mut plain = highlighter.clone();
+            plain.with_level(HighlightLevel::Off);
+            let plain = unit.measure(&plain.highlight(INPUT).unwrap());
+            // Below that, single lines don't fit into a message.
+            for limit in 80..plain + 10 {
+                let mut highlighter = highlighter.clone();
+                let messages = highlighter
+                    .with_soft_limit(limit)
+                    .highlight_messages(INPUT)
+                    .unwrap();
+                assert_eq!(messages.len() == 1, plain < limit, "{limit}");
+                for message in &messages {
+                    assert!(unit.measure(message) < limit, "{limit}: {message:?}");
+                }
+                let stripped: Vec<_> = messages.iter().map(|m| strip(m, discord)).collect();
+                assert_eq!(stripped.concat(), INPUT, "{limit}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_prefers_top_level_nodes() {
+        let function = INPUT.find("#let").unwrap()..INPUT.find("}\n").unwrap() + 2;
+        let raw = INPUT.find("```").unwrap()..INPUT.rfind("```").unwrap() + 3;
+        let inside = |cut: usize, node: &Range<usize>| node.start < cut && cut < node.end;
+        let plan = Plan::new(HighlightLevel::Off);
+        for (highlighter, _) in highlighters() {
+            let parsed = highlighter.parse(INPUT);
+            let linked = LinkedNode::new(&parsed);
+            let size = |range: Range<usize>| {
+                let message = highlighter
+                    .annotate(&linked, Some(range), &[], None)
+                    .unwrap();
+                highlighter.measure(&message, &plan)
+            };
+            let line_end =
+                |pos: usize| INPUT[pos..].find('\n').map_or(INPUT.len(), |i| pos + i + 1);
+
+            for limit in 80..400 {
+                let ranges = highlighter.split(&linked, limit).unwrap();
+                assert_eq!(ranges.first().unwrap().start, 0);
+                assert_eq!(ranges.last().unwrap().end, INPUT.len());
+                for pair in ranges.windows(2) {
+                    let (range, cut) = (&pair[0], pair[0].end);
+                    assert_eq!(cut, pair[1].start);
+                    assert!(size(range.clone()) < limit, "{limit}: {ranges:?}");
+                    // The function is short enough to never be split.
+                    assert!(!inside(cut, &function), "{limit}: {ranges:?}");
+                    // A message only ends early between top-level nodes.
+                    if inside(cut, &raw) {
+                        let next = line_end(cut);
+                        assert!(size(range.start..next) >= limit, "{limit}: {ranges:?}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_at_any_line_break() {
+        for newline in ["\r", "\r\n", "\u{2028}"] {
+            let input = ["First line.", "Second line.", "Third line.", ""].join(newline);
+            let messages = Highlighter::default()
+                .with_soft_limit(20)
+                .highlight_messages(&input)
+                .unwrap();
+            let expected: Vec<_> = input.split_inclusive(newline).collect();
+            assert_eq!(messages, expected, "{newline:?}");
+        }
+    }
+}
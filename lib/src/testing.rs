@@ -0,0 +1,16 @@
+//! Helpers shared by the tests of several modules.
+
+/// Remove the SGR escape sequences from highlighted output.
+pub(crate) fn strip_sgr(highlighted: &str) -> String {
+    let mut text = String::new();
+    let mut rest = highlighted;
+    while let Some(start) = rest.find('\x1b') {
+        text.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('m')
+            .expect("only SGR sequences are written");
+        rest = &rest[start + end + 1..];
+    }
+    text.push_str(rest);
+    text
+}
@@ -0,0 +1,250 @@
+use std::ops::Range;
+
+use typst_syntax::LinkedNode;
+
+use crate::{
+    annotate::Annotated,
+    fit::{last_fitting, Plan},
+    lines::line_ranges,
+    Error, Highlighter,
+};
+
+/// Where the output is cut off to stay below the hard limit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Truncation {
+    /// The source position at which the output is cut off.
+    pub(crate) cut: usize,
+    /// The marker that is written in place of the rest of the output.
+    ///
+    /// It is empty if no source text is cut off.
+    pub(crate) marker: String,
+}
+
+impl Truncation {
+    /// Cut off the text of a node at the offset at a source position, up to the end.
+    fn new(text: &str, offset: usize, cut: usize, end: usize) -> Truncation {
+        let rest = &text[cut - offset..end - offset];
+        let marker = if rest.is_empty() {
+            // Only the Discord wrapper is cut off, so there is nothing to mark.
+            String::new()
+        } else if cut == offset || text[..cut - offset].ends_with(typst_syntax::is_newline) {
+            // Only the text ending with a line break has an empty last line.
+            let lines = line_ranges(rest)
+                .iter()
+                .filter(|line| !line.is_empty())
+                .count();
+            let s = if lines == 1 { "" } else { "s" };
+            format!("// {lines} more line{s}\n")
+        } else {
+            "…".to_string()
+        };
+        Truncation { cut, marker }
+    }
+}
+
+impl Highlighter {
+    /// Cut the output off at the last end of a line at which it stays below the hard limit.
+    ///
+    /// If not even the first line fits, it is cut off at the last end of a top-level node instead.
+    /// Returns the truncated output along with where it was cut off.
+    pub(crate) fn truncate(
+        &self,
+        node: &LinkedNode,
+        range: Range<usize>,
+        annotated: &Annotated,
+        plan: &Plan,
+        elided: &[Range<usize>],
+        hard_limit: usize,
+    ) -> Result<(Annotated, Truncation), Error> {
+        let unit = self.size_unit();
+        let text = node.get().clone().into_text();
+        let offset = node.offset();
+
+        let cuts = |ends: &mut dyn Iterator<Item = usize>| {
+            let mut cuts: Vec<usize> = ends
+                .filter(|cut| range.start < *cut && *cut < range.end)
+                // Cutting off inside of an elided region would only leave its placeholder.
+                .filter(|cut| {
+                    !elided
+                        .iter()
+                        .any(|region| region.start < *cut && *cut <= region.end)
+                })
+                .collect();
+            cuts.push(range.start);
+            cuts.sort_unstable();
+            cuts.dedup();
+            cuts
+        };
+        let line_ends = cuts(&mut line_ranges(&text).into_iter().map(|line| offset + line.end));
+        let node_ends = cuts(&mut node.children().map(|child| child.range().end));
+
+        let truncation = |cut: usize| Truncation::new(&text, offset, cut, range.end);
+
+        // Estimate where to cut from the whole output first,
+        // so that only few cuts need to be highlighted to know their actual size.
+        let styles = self.resolve_styles(annotated, plan);
+        let fit = |cuts: &[usize]| -> Result<(Annotated, Truncation), Error> {
+            let attempt = |i: usize| -> Result<(Annotated, Truncation), Error> {
+                let truncation = truncation(cuts[i]);
+                let truncated = self.annotate(
+                    node,
+                    Some(range.start..truncation.cut),
+                    elided,
+                    Some(&truncation.marker),
+                )?;
+                Ok((truncated, truncation))
+            };
+            let last = last_fitting(
+                cuts,
+                |&cut| {
+                    let Truncation { marker, .. } = truncation(cut);
+                    annotated.size_until(&styles, unit, cut) + self.measure_marker(&marker, plan)
+                        < hard_limit
+                },
+                |i| {
+                    let (truncated, _) = attempt(i)?;
+                    Ok(self.measure(&truncated, plan) < hard_limit)
+                },
+            )?;
+            if let Some(i) = last {
+                return attempt(i);
+            }
+
+            // Not even the marker alone fits, so fall back to a shorter one or none at all.
+            let Truncation { cut, marker } = truncation(cuts[0]);
+            for marker in [marker, "…".to_string()] {
+                let truncated =
+                    self.annotate(node, Some(range.start..cut), elided, Some(&marker))?;
+                if self.measure(&truncated, plan) < hard_limit {
+                    return Ok((truncated, Truncation { cut, marker }));
+                }
+            }
+            let truncated = self.annotate(node, Some(range.start..cut), elided, None)?;
+            let marker = String::new();
+            Ok((truncated, Truncation { cut, marker }))
+        };
+
+        let (truncated, truncation) = fit(&line_ends)?;
+        if truncation.cut > range.start {
+            return Ok((truncated, truncation));
+        }
+        fit(&node_ends)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUT: &str = "= Title\n#let f(x) = [*strong* _emph_ #x]\n\
+        ```rust\nfn main() {\n    println!(\"{}\", 1);\n}\n```\n\
+        Some more text with $x^2$.\n\n```\nplain\n```\nThe end.\n";
+
+    /// The source position at which the output is cut off to stay below the hard limit,
+    /// along with the last line end at which the rendered output actually stays below it.
+    fn cuts(highlighter: &Highlighter, hard_limit: usize) -> (usize, Option<usize>) {
+        let mut highlighter = highlighter.clone();
+        highlighter.with_hard_limit(hard_limit);
+        let parsed = highlighter.parse(INPUT);
+        let linked = LinkedNode::new(&parsed);
+        let fitted = highlighter.fit(&linked, None).unwrap();
+        let truncation = fitted.truncation.expect("the output should be truncated");
+
+        let fits = |cut: usize| {
+            let Truncation { marker, .. } = Truncation::new(INPUT, 0, cut, INPUT.len());
+            let truncated = highlighter
+                .annotate(&linked, Some(0..cut), &[], Some(&marker))
+                .unwrap();
+            highlighter.measure(&truncated, &fitted.plan) < hard_limit
+        };
+        let expected = INPUT
+            .match_indices('\n')
+            .map(|(i, _)| i + 1)
+            .rev()
+            .find(|&cut| cut < INPUT.len() && fits(cut));
+        (truncation.cut, expected)
+    }
+
+    #[test]
+    fn test_truncate_at_last_fitting_line() {
+        let mut discord = Highlighter::default();
+        discord.for_discord();
+        let size = INPUT.chars().count();
+        for highlighter in [Highlighter::default(), discord] {
+            let mut tested = 0;
+            for hard_limit in 40..size {
+                let (cut, expected) = cuts(&highlighter, hard_limit);
+                if let Some(expected) = expected {
+                    assert_eq!(cut, expected, "{hard_limit} {highlighter:?}");
+                    tested += 1;
+                }
+            }
+            assert!(tested > size / 2);
+        }
+    }
+
+    #[test]
+    fn test_truncate_before_raw_block() {
+        // The note in front of the raw block in Discord output doesn't count before it.
+        let output = Highlighter::default()
+            .for_discord()
+            .with_hard_limit(100)
+            .highlight(INPUT)
+            .unwrap();
+        assert!(output.contains("#let f(x) = [*strong* _emph_ #x]\n"));
+        assert!(output.contains("// 11 more lines\n"));
+        assert!(output.chars().count() < 100);
+    }
+
+    #[test]
+    fn test_truncate_empty_input() {
+        let mut discord = Highlighter::default();
+        discord.for_discord();
+        for (highlighter, hard_limit) in [(discord, 5), (Highlighter::default(), 0)] {
+            let unlimited = highlighter.highlight("").unwrap();
+            let mut highlighter = highlighter.clone();
+            highlighter.with_hard_limit(hard_limit);
+            let output = highlighter.highlight("").unwrap();
+            // Only the Discord wrapper exceeds the limit, so nothing is marked as cut off.
+            assert_eq!(output, unlimited);
+            let chunks = highlighter.highlight_chunks("");
+            assert_eq!(chunks.collect::<Result<String, _>>().unwrap(), output);
+        }
+    }
+
+    #[test]
+    fn test_truncate_with_plain_marker() {
+        // Without any colors left, the marker is not colored either.
+        let output = Highlighter::default()
+            .with_soft_limit(5)
+            .with_hard_limit(40)
+            .highlight("= Title\nSome text.\nMore text.\nEven more text.\n")
+            .unwrap();
+        assert_eq!(output, "= Title\nSome text.\n// 2 more lines\n");
+
+        // Markers that don't fit fall back to shorter ones.
+        let input = "line one\nline two\n";
+        let mut highlighter = Highlighter::default();
+        for (hard_limit, expected) in [(12, "line one…"), (4, "…"), (2, "")] {
+            let output = highlighter
+                .with_hard_limit(hard_limit)
+                .highlight(input)
+                .unwrap();
+            assert_eq!(output, expected);
+            let chunks = highlighter.highlight_chunks(input);
+            assert_eq!(chunks.collect::<Result<String, _>>().unwrap(), output);
+        }
+    }
+
+    #[test]
+    fn test_truncate_at_any_line_break() {
+        for newline in ["\r", "\r\n", "\u{2028}"] {
+            let input = ["First line.", "Second line.", "", "Third line."].join(newline);
+            let output = Highlighter::default()
+                .with_hard_limit(35)
+                .highlight(&input)
+                .unwrap();
+            assert_eq!(output, format!("First line.{newline}// 3 more lines\n"));
+        }
+    }
+}
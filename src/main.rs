@@ -1,8 +1,8 @@
-use std::{io::Read, path::PathBuf};
+use std::{io::Read, ops::Range, path::PathBuf};
 
 use clap::{ArgAction, Parser, ValueEnum};
-use color_eyre::eyre::{Context as _, Result};
-use typst_ansi_hl::Highlighter;
+use color_eyre::eyre::{eyre, Context as _, Result};
+use typst_ansi_hl::{Degradation, ErrorPolicy, HighlightReport, Highlighter};
 
 #[derive(clap::Parser)]
 struct Args {
@@ -55,7 +55,7 @@ struct Args {
     #[doc(hidden)]
     _no_unindent: bool,
 
-    /// Softly enforce a byte size limit.
+    /// Softly enforce a size limit.
     ///
     /// This means that if the size limit is exceeded, less colors are used
     /// in order to get below that size limit.
@@ -63,9 +63,142 @@ struct Args {
     #[clap(short = 'l', long)]
     soft_limit: Option<usize>,
 
+    /// Strictly enforce a size limit.
+    ///
+    /// Like with `--soft-limit`, less colors are used in order to get below that size limit.
+    /// If it is not possible to get below that limit, the output is cut off
+    /// at the end of a line or a top-level node and marked as truncated.
+    #[clap(long)]
+    hard_limit: Option<usize>,
+
+    /// Elide long content blocks, strings, raw blocks and function bodies to get below the limits.
+    ///
+    /// Either before less colors are used, or only once the output exceeds the limits even without colors.
+    #[clap(long, value_name = "WHEN")]
+    elide: Option<Elision>,
+
+    /// The highest level to highlight at.
+    ///
+    /// Without `--soft-limit` or `--hard-limit`, the output is highlighted at this level.
+    #[clap(long, value_name = "LEVEL")]
+    level: Option<HighlightLevel>,
+
+    /// The unit that the soft and hard limits are measured in. [default: chars with `--discord`, bytes otherwise]
+    #[clap(long, value_name = "UNIT")]
+    size_unit: Option<SizeUnit>,
+
     /// The kind of input syntax.
     #[clap(short, long, default_value = "markup")]
     mode: SyntaxMode,
+
+    /// Only output the given lines, e.g. `120:140`, `120:` or `:140`.
+    ///
+    /// Lines are counted from 1 and both ends are inclusive.
+    /// The whole input is still parsed, so that the lines are highlighted correctly.
+    #[clap(long, value_name = "START:END", value_parser = parse_line_range)]
+    #[clap(conflicts_with = "byte_range")]
+    line_range: Option<RangeArg>,
+
+    /// Only output the given bytes, e.g. `100:250`, `100:` or `:250`.
+    ///
+    /// Bytes are counted from 0 and the end is exclusive.
+    /// The whole input is still parsed, so that the bytes are highlighted correctly.
+    #[clap(long, value_name = "START:END", value_parser = parse_byte_range)]
+    byte_range: Option<RangeArg>,
+
+    /// Load additional syntaxes to highlight raw blocks with.
+    ///
+    /// Either a directory containing `.sublime-syntax` files, which are added to the built-in syntaxes,
+    /// or a dump created with `--dump-syntaxes`.
+    #[clap(long, value_name = "PATH")]
+    syntaxes: Option<PathBuf>,
+
+    /// Write the syntaxes loaded with `--syntaxes` to a dump file and exit.
+    ///
+    /// Passing the dump to `--syntaxes` is much faster than loading the syntax files.
+    #[clap(long, value_name = "FILE", requires = "syntaxes")]
+    dump_syntaxes: Option<PathBuf>,
+
+    /// Highlight raw blocks tagged with one language as another, e.g. `rs2024=rust`.
+    ///
+    /// The target is the name of a syntax or a language tag it is known by.
+    /// Can be given multiple times.
+    #[clap(long, value_name = "FROM=TO", value_parser = parse_alias)]
+    lang_alias: Vec<(String, String)>,
+
+    /// Highlight raw blocks with an unknown language tag as this language.
+    #[clap(long, value_name = "LANG")]
+    fallback_lang: Option<String>,
+
+    /// Detect the language of raw blocks without a language tag from their first line.
+    #[clap(long)]
+    detect_first_line: bool,
+
+    /// Guess the language of raw blocks without a language tag from their content.
+    ///
+    /// Guesses below the given confidence between 0 and 1 are not highlighted.
+    /// If no confidence is given, 0.5 is used.
+    #[clap(long, value_name = "CONFIDENCE", num_args = 0..=1, require_equals = true)]
+    #[clap(default_missing_value = "0.5")]
+    guess_lang: Option<f32>,
+
+    /// Fail if a raw block cannot be highlighted, instead of printing it uncolored.
+    #[clap(long)]
+    strict: bool,
+
+    /// Print how the input was highlighted to stderr.
+    ///
+    /// This includes the highlight level, the output size, whether the soft limit was met,
+    /// elided regions, unknown raw block languages and any warnings.
+    #[clap(long)]
+    report: bool,
+}
+
+fn parse_alias(arg: &str) -> Result<(String, String), String> {
+    let (from, to) = arg
+        .split_once('=')
+        .ok_or_else(|| "expected an alias like `FROM=TO`".to_string())?;
+    Ok((from.to_string(), to.to_string()))
+}
+
+/// A range with optional bounds, as given on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RangeArg {
+    start: Option<usize>,
+    end: Option<usize>,
+}
+
+fn parse_range(arg: &str) -> Result<RangeArg, String> {
+    let (start, end) = arg
+        .split_once(':')
+        .ok_or_else(|| "expected a range like `START:END`".to_string())?;
+    let parse_bound = |bound: &str| {
+        (!bound.is_empty())
+            .then(|| bound.parse::<usize>().map_err(|err| err.to_string()))
+            .transpose()
+    };
+    let range = RangeArg {
+        start: parse_bound(start)?,
+        end: parse_bound(end)?,
+    };
+    if let (Some(start), Some(end)) = (range.start, range.end) {
+        if end < start {
+            return Err(format!("the end {end} is before the start {start}"));
+        }
+    }
+    Ok(range)
+}
+
+fn parse_line_range(arg: &str) -> Result<RangeArg, String> {
+    let range = parse_range(arg)?;
+    if range.start == Some(0) || range.end == Some(0) {
+        return Err("lines are counted from 1".to_string());
+    }
+    Ok(range)
+}
+
+fn parse_byte_range(arg: &str) -> Result<RangeArg, String> {
+    parse_range(arg)
 }
 
 /// The kind of input syntax.
@@ -86,10 +219,89 @@ impl From<SyntaxMode> for typst_ansi_hl::SyntaxMode {
     }
 }
 
+/// When to elide long regions of the code.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Elision {
+    BeforeColors,
+    AfterColors,
+}
+
+impl From<Elision> for typst_ansi_hl::Elision {
+    fn from(value: Elision) -> Self {
+        match value {
+            Elision::BeforeColors => typst_ansi_hl::Elision::BeforeColors,
+            Elision::AfterColors => typst_ansi_hl::Elision::AfterColors,
+        }
+    }
+}
+
+/// What things to highlight, from less to more.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum HighlightLevel {
+    Off,
+    L0,
+    L1,
+    L2,
+    L3,
+    WithRaw,
+    WithStyles,
+    All,
+}
+
+impl From<HighlightLevel> for typst_ansi_hl::HighlightLevel {
+    fn from(value: HighlightLevel) -> Self {
+        match value {
+            HighlightLevel::Off => typst_ansi_hl::HighlightLevel::Off,
+            HighlightLevel::L0 => typst_ansi_hl::HighlightLevel::L0,
+            HighlightLevel::L1 => typst_ansi_hl::HighlightLevel::L1,
+            HighlightLevel::L2 => typst_ansi_hl::HighlightLevel::L2,
+            HighlightLevel::L3 => typst_ansi_hl::HighlightLevel::L3,
+            HighlightLevel::WithRaw => typst_ansi_hl::HighlightLevel::WithRaw,
+            HighlightLevel::WithStyles => typst_ansi_hl::HighlightLevel::WithStyles,
+            HighlightLevel::All => typst_ansi_hl::HighlightLevel::All,
+        }
+    }
+}
+
+/// The unit that sizes are measured in.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum SizeUnit {
+    Bytes,
+    Chars,
+    Utf16,
+    Graphemes,
+}
+
+impl From<SizeUnit> for typst_ansi_hl::SizeUnit {
+    fn from(value: SizeUnit) -> Self {
+        match value {
+            SizeUnit::Bytes => typst_ansi_hl::SizeUnit::Bytes,
+            SizeUnit::Chars => typst_ansi_hl::SizeUnit::Chars,
+            SizeUnit::Utf16 => typst_ansi_hl::SizeUnit::Utf16,
+            SizeUnit::Graphemes => typst_ansi_hl::SizeUnit::Graphemes,
+        }
+    }
+}
+
 fn main() -> Result<()> {
     color_eyre::install()?;
 
     let args = Args::parse();
+
+    let syntax_set = args
+        .syntaxes
+        .as_ref()
+        .map(|path| {
+            typst_ansi_hl::load_syntaxes(path)
+                .wrap_err_with(|| format!("failed to load syntaxes from `{}`", path.display()))
+        })
+        .transpose()?;
+    if let (Some(path), Some(syntax_set)) = (&args.dump_syntaxes, &syntax_set) {
+        typst_ansi_hl::dump_syntaxes(syntax_set, path)
+            .wrap_err_with(|| format!("failed to dump syntaxes to `{}`", path.display()))?;
+        return Ok(());
+    }
+
     let mut input = String::new();
     if let Some(path) = &args.input {
         std::fs::File::open(path)
@@ -129,13 +341,118 @@ fn main() -> Result<()> {
     if let Some(soft_limit) = args.soft_limit {
         highlighter.with_soft_limit(soft_limit);
     }
-    highlighter
-        .highlight_to(stripped, out)
-        .wrap_err("failed to highlight input")?;
+    if let Some(hard_limit) = args.hard_limit {
+        highlighter.with_hard_limit(hard_limit);
+    }
+    if let Some(level) = args.level {
+        highlighter.with_level(level.into());
+    }
+    if let Some(elision) = args.elide {
+        highlighter.with_elision(elision.into());
+    }
+    if let Some(unit) = args.size_unit {
+        highlighter.with_size_unit(unit.into());
+    }
+    if let Some(syntax_set) = syntax_set {
+        highlighter.with_syntax_set(syntax_set);
+    }
+    for (from, to) in args.lang_alias {
+        highlighter.with_lang_alias(from, to);
+    }
+    if let Some(lang) = args.fallback_lang {
+        highlighter.with_fallback_lang(lang);
+    }
+    if args.detect_first_line {
+        highlighter.with_first_line_detection();
+    }
+    if let Some(threshold) = args.guess_lang {
+        highlighter.with_lang_guessing(threshold);
+    }
+    if args.strict {
+        highlighter.with_error_policy(ErrorPolicy::Strict);
+    }
+
+    let range = if let Some(lines) = args.line_range {
+        Some(line_range_to_bytes(stripped, lines).map_err(|err| eyre!(err))?)
+    } else {
+        args.byte_range
+            .map(|bytes| bytes.start.unwrap_or(0)..bytes.end.unwrap_or(stripped.len()))
+    };
+    let report = match range {
+        Some(range) => highlighter.highlight_range_to_with_report(stripped, range, out),
+        None => highlighter.highlight_to_with_report(stripped, out),
+    }
+    .wrap_err("failed to highlight input")?;
+    if args.report {
+        eprint!(
+            "{}",
+            format_report(&report, args.soft_limit, args.hard_limit)
+        );
+    }
 
     Ok(())
 }
 
+/// Describe how the input was highlighted, one line per item.
+fn format_report(
+    report: &HighlightReport,
+    soft_limit: Option<usize>,
+    hard_limit: Option<usize>,
+) -> String {
+    let mut out = format!("highlight level: {:?}\n", report.level);
+    let unit = match report.unit {
+        typst_ansi_hl::SizeUnit::Bytes => "bytes",
+        typst_ansi_hl::SizeUnit::Chars => "chars",
+        typst_ansi_hl::SizeUnit::Utf16 => "UTF-16 code units",
+        typst_ansi_hl::SizeUnit::Graphemes => "grapheme clusters",
+    };
+    out += &format!("output size: {} {unit}", report.size);
+    for (kind, limit) in [("soft", soft_limit), ("hard", hard_limit)] {
+        match limit {
+            Some(limit) if report.size < limit => {
+                out += &format!(" (below the {kind} limit of {limit})")
+            }
+            Some(limit) => out += &format!(" (exceeds the {kind} limit of {limit})"),
+            None => {}
+        }
+    }
+    out.push('\n');
+    if !report.elided.is_empty() {
+        let elided: Vec<_> = report
+            .elided
+            .iter()
+            .map(|range| format!("{}..{}", range.start, range.end))
+            .collect();
+        out += &format!("elided bytes: {}\n", elided.join(", "));
+    }
+    if let Some(pos) = report.truncated_at {
+        out += &format!("truncated at byte {pos}\n");
+    }
+
+    if !report.degradations.is_empty() {
+        let degradations: Vec<_> = report
+            .degradations
+            .iter()
+            .map(|degradation| match degradation {
+                Degradation::Styles(tag) => format!("styles of {tag:?}"),
+                Degradation::Color(tag) => format!("color of {tag:?}"),
+                Degradation::Raw(pos) => format!("raw block at byte {pos}"),
+            })
+            .collect();
+        out += &format!("degraded: {}\n", degradations.join(", "));
+    }
+    if !report.unknown_langs.is_empty() {
+        out += &format!(
+            "unknown raw block languages: {}\n",
+            report.unknown_langs.join(", ")
+        );
+    }
+    for warning in &report.warnings {
+        out += &format!("warning: {warning}\n");
+    }
+    out
+}
+
 fn unwrap_codeblock(input: &str) -> &str {
     let Some(rest) = input.strip_prefix("```ansi\n") else {
         return input;
@@ -147,6 +464,35 @@ fn unwrap_codeblock(input: &str) -> &str {
     rest
 }
 
+/// Converts a range of lines, counted from 1 and inclusive on both ends, to a byte range.
+///
+/// Just like byte ranges, lines past the end of the input are rejected.
+fn line_range_to_bytes(input: &str, lines: RangeArg) -> Result<Range<usize>, String> {
+    let line_count = input.split_inclusive('\n').count();
+    let first = lines.start.unwrap_or(1);
+    let last = lines.end.unwrap_or(line_count);
+    if first > line_count.max(1) || last > line_count {
+        return Err(format!(
+            "the line range {first}:{last} is past the end of the input with {line_count} lines"
+        ));
+    }
+
+    let mut start = input.len();
+    let mut end = input.len();
+    let mut offset = 0;
+    for (i, line) in input.split_inclusive('\n').enumerate() {
+        if i + 1 == first {
+            start = offset;
+        }
+        offset += line.len();
+        if i + 1 == last {
+            end = offset;
+            break;
+        }
+    }
+    Ok(start..end)
+}
+
 /// Removes any indentation present on all non-empty lines.
 ///
 /// The same kinds of whitespaces must be present on all lines.
@@ -190,4 +536,64 @@ mod tests {
         assert_eq!(unindent("    hello\n  world"), "  hello\nworld");
         assert_eq!(unindent("  hello\n \tworld"), " hello\n\tworld");
     }
+
+    #[test]
+    fn test_line_range_to_bytes() {
+        let lines = |start, end| RangeArg { start, end };
+        let input = "a\nbb\nccc\n";
+        assert_eq!(line_range_to_bytes(input, lines(None, None)), Ok(0..9));
+        assert_eq!(
+            line_range_to_bytes(input, lines(Some(1), Some(1))),
+            Ok(0..2)
+        );
+        assert_eq!(line_range_to_bytes(input, lines(Some(2), None)), Ok(2..9));
+        assert_eq!(line_range_to_bytes(input, lines(None, Some(2))), Ok(0..5));
+        assert_eq!(
+            line_range_to_bytes(input, lines(Some(2), Some(3))),
+            Ok(2..9)
+        );
+        assert_eq!(
+            line_range_to_bytes("a\nb", lines(Some(2), Some(2))),
+            Ok(2..3)
+        );
+        assert_eq!(line_range_to_bytes("", lines(None, None)), Ok(0..0));
+
+        // Ranges past the end are rejected, just like byte ranges.
+        assert!(line_range_to_bytes(input, lines(Some(5), Some(9))).is_err());
+        assert!(line_range_to_bytes(input, lines(Some(2), Some(4))).is_err());
+        assert!(line_range_to_bytes(input, lines(Some(4), None)).is_err());
+        let bytes = parse_byte_range("5:20").unwrap();
+        let highlighted = Highlighter::default().highlight_range(input, 5..bytes.end.unwrap());
+        assert!(highlighted.is_err());
+
+        // Empty and reversed ranges are rejected while parsing.
+        assert_eq!(parse_line_range("2:").unwrap(), lines(Some(2), None));
+        assert!(parse_line_range("2:0").is_err());
+        assert!(parse_line_range("0:2").is_err());
+        assert!(parse_line_range("3:2").is_err());
+        assert!(parse_byte_range("3:2").is_err());
+        assert_eq!(parse_byte_range("0:0").unwrap(), lines(Some(0), Some(0)));
+    }
+
+    #[test]
+    fn test_format_report() {
+        let mut out = termcolor::Ansi::new(Vec::new());
+        let report = Highlighter::default()
+            .with_soft_limit(10)
+            .highlight_to_with_report("= Title\n```foo bar```", &mut out)
+            .unwrap();
+        let formatted = format_report(&report, Some(10), None);
+        assert!(formatted.starts_with("highlight level: Off\n"));
+        assert!(formatted.contains("(exceeds the soft limit of 10)"));
+        assert!(formatted.contains("unknown raw block languages: foo\n"));
+    }
+
+    #[test]
+    fn test_parse_alias() {
+        let alias = |from: &str, to: &str| Ok((from.to_string(), to.to_string()));
+        assert_eq!(parse_alias("rs2024=rust"), alias("rs2024", "rust"));
+        assert_eq!(parse_alias("a=b=c"), alias("a", "b=c"));
+        assert_eq!(parse_alias("=rust"), alias("", "rust"));
+        assert!(parse_alias("rust").unwrap_err().contains("`FROM=TO`"));
+    }
 }